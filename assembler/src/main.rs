@@ -0,0 +1,468 @@
+#![feature(rustc_private)]
+
+//! A command line assembler for the dynasm-rs assembly syntax. The input is parsed and compiled
+//! by the same code the `dynasm!` macro uses, after which the resulting statements are executed
+//! directly against a `VecAssembler` instead of being turned into Rust code. As there is no
+//! Rust compiler to evaluate expressions, they are limited to integer literals, constants
+//! defined on the command line and arithmetic on those.
+
+extern crate syntax;
+extern crate dynasm;
+extern crate dynasmrt;
+
+use syntax::ast;
+use syntax::codemap::Span;
+use syntax::ext::base::{ExtCtxt, DummyResolver};
+use syntax::ext::expand::ExpansionConfig;
+use syntax::parse::{self, ParseSess};
+
+use dynasm::{parser, compiler};
+use dynasm::compiler::Stmt;
+use dynasmrt::{DynasmApi, DynasmLabelApi, DynamicLabel, VecAssembler};
+
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+
+const USAGE: &'static str = "\
+usage: dynasm-asm [options] <input>
+
+Assembles a file written in dynasm syntax, with one item per line.
+
+options:
+    -o <file>          write the output to <file> instead of stdout
+    -f raw|hex|elf     output raw bytes (default), a hex dump or an ELF relocatable object
+    -D <name>=<value>  define a named constant that can be used in expressions
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Raw,
+    Hex,
+    Elf
+}
+
+struct Options {
+    input: String,
+    output: Option<String>,
+    format: Format,
+    constants: HashMap<String, i64>
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(msg) => {
+            let _ = writeln!(io::stderr(), "error: {}\n\n{}", msg, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let mut source = String::new();
+    if let Err(e) = File::open(&options.input).and_then(|mut f| f.read_to_string(&mut source)) {
+        let _ = writeln!(io::stderr(), "error: could not read '{}': {}", options.input, e);
+        process::exit(1);
+    }
+
+    let code = match assemble(&options, &source) {
+        Some(code) => code,
+        None => process::exit(1)
+    };
+
+    let result = match options.output {
+        Some(ref path) => File::create(path).and_then(|mut f| f.write_all(&code)),
+        None => io::stdout().write_all(&code)
+    };
+    if let Err(e) = result {
+        let _ = writeln!(io::stderr(), "error: could not write the output: {}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut output = None;
+    let mut format = Format::Raw;
+    let mut constants = HashMap::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "-o" => output = Some(args.next().ok_or("-o requires an argument")?),
+            "-f" => format = match &*args.next().ok_or("-f requires an argument")? {
+                "raw" => Format::Raw,
+                "hex" => Format::Hex,
+                "elf" => Format::Elf,
+                f => return Err(format!("unknown output format '{}'", f))
+            },
+            "-D" => {
+                let def = args.next().ok_or("-D requires an argument")?;
+                let mut parts = def.splitn(2, '=');
+                let name = parts.next().unwrap().to_string();
+                let value = parts.next().ok_or_else(|| format!("'{}' should be of the form name=value", def))?;
+                let value = parse_int(value).ok_or_else(|| format!("'{}' is not an integer", value))?;
+                constants.insert(name, value);
+            },
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err("only a single input file can be given".into())
+        }
+    }
+
+    Ok(Options {
+        input: input.ok_or("no input file given")?,
+        output: output,
+        format: format,
+        constants: constants
+    })
+}
+
+fn parse_int(s: &str) -> Option<i64> {
+    let (negative, s) = if s.starts_with('-') { (true, &s[1..]) } else { (false, s) };
+    let value = if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u64>()
+    };
+    let value = match value {
+        Ok(value) => value as i64,
+        Err(_) => return None
+    };
+    Some(if negative { value.wrapping_neg() } else { value })
+}
+
+// parses, compiles and assembles the source, returning the output in the requested format.
+// errors are reported through the diagnostics of the parsing session.
+fn assemble(options: &Options, source: &str) -> Option<Vec<u8>> {
+    // parser::parse expects the name of the assembler, followed by items that each start
+    // with a semicolon. One is prepended to every line that doesn't start with one, while
+    // keeping the line numbers intact.
+    let mut wrapped = String::from("ops ");
+    for line in source.lines() {
+        let trimmed = line.trim_left();
+        if !trimmed.is_empty() && !trimmed.starts_with(';') && !trimmed.starts_with("//") {
+            wrapped.push_str("; ");
+        }
+        wrapped.push_str(line);
+        wrapped.push('\n');
+    }
+
+    let sess = ParseSess::new();
+    let mut resolver = DummyResolver;
+    let ecx = ExtCtxt::new(&sess, Default::default(), ExpansionConfig::default("dynasm_asm".to_string()), &mut resolver);
+
+    let mut p = parse::new_parser_from_source_str(&sess, Default::default(), options.input.clone(), wrapped);
+    let items = match parser::parse(&ecx, &mut p) {
+        Ok((_, items)) => items,
+        Err(mut e) => {
+            e.emit();
+            return None;
+        }
+    };
+    let stmts = match compiler::compile(&ecx, items) {
+        Ok(stmts) => stmts,
+        Err(()) => return None
+    };
+    if sess.span_diagnostic.has_errors() {
+        return None;
+    }
+
+    let mut state = State {
+        ops: VecAssembler::new(0),
+        constants: &options.constants,
+        dynamic_labels: HashMap::new()
+    };
+    if let Err((span, msg)) = state.execute_all(stmts) {
+        sess.span_diagnostic.span_err(span, &msg);
+        return None;
+    }
+
+    let mut ops = state.ops;
+    Some(match options.format {
+        Format::Raw => ops.finalize(),
+        Format::Hex => {
+            let mut out = String::new();
+            for (i, line) in ops.finalize().chunks(16).enumerate() {
+                let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                out.push_str(&format!("{:08x}: {}\n", i * 16, bytes.join(" ")));
+            }
+            out.into_bytes()
+        },
+        Format::Elf => {
+            let mut out = Vec::new();
+            ops.write_elf(&mut out, &[]).expect("Failed to write the ELF object");
+            out
+        }
+    })
+}
+
+type EvalResult<T> = Result<T, (Span, String)>;
+
+struct State<'a> {
+    ops: VecAssembler,
+    constants: &'a HashMap<String, i64>,
+    // dynamic labels are referred to by number
+    dynamic_labels: HashMap<i64, DynamicLabel>
+}
+
+impl<'a> State<'a> {
+    // performs the same operations as the code generated by serialize::serialize
+    fn execute(&mut self, stmt: Stmt) -> EvalResult<()> {
+        match stmt {
+            Stmt::Const(byte)      => self.ops.push(byte),
+            Stmt::ExprConst(expr)  => {
+                let value = self.eval(&expr)?;
+                self.ops.push(value as u8);
+            },
+            Stmt::Var(expr, parser::Size::OWORD) => {
+                let value = self.eval_wide(&expr)?;
+                self.ops.push_u128(value);
+            },
+            Stmt::Var(expr, parser::Size::HWORD) => {
+                // like the [u128; 2] this is in Rust code, the least significant half first
+                let halves = match expr.node {
+                    ast::ExprKind::Vec(ref halves) if halves.len() == 2 => [self.eval_wide(&halves[0])?, self.eval_wide(&halves[1])?],
+                    _ => return Err((expr.span, "'.hword' values have to be arrays of two values".into()))
+                };
+                self.ops.push_u256(halves);
+            },
+            Stmt::Var(expr, size)  => {
+                let value = self.eval(&expr)?;
+                for i in 0..size.in_bytes() as u32 {
+                    self.ops.push((value >> cmp::min(i * 8, 63)) as u8);
+                }
+            },
+            Stmt::Float(expr, size) => {
+                let value = self.eval_float(&expr)?;
+                match size {
+                    parser::Size::DWORD => self.ops.push_f32(value as f32),
+                    parser::Size::QWORD => self.ops.push_f64(value),
+                    _           => self.ops.push_f80(value)
+                }
+            },
+            Stmt::Fill(count, size, value) => {
+                let count = self.eval(&count)?;
+                let value = self.eval(&value)?;
+                for _ in 0..count {
+                    for i in 0..size as u32 {
+                        self.ops.push((value >> (i * 8)) as u8);
+                    }
+                }
+            },
+            Stmt::RuntimeError(span, msg) => return Err((span, msg.into())),
+            Stmt::Extend(expr)     => return Err((expr.span, "'.bytes' and strings that aren't literals are not supported outside of Rust code".into())),
+            Stmt::DynScale(scale, rest) => {
+                let encoded = match self.eval(&scale)? {
+                    1 => 0,
+                    2 => 1,
+                    4 => 2,
+                    8 => 3,
+                    _ => return Err((scale.span, "Type size not representable as scale".into()))
+                };
+                let rest = self.eval(&rest)?;
+                self.ops.push(rest as u8 | encoded << 6);
+            },
+
+            Stmt::Align(expr)      => {
+                let alignment = self.eval(&expr)?;
+                self.ops.align(alignment as usize);
+            },
+            Stmt::AlignPatchable(size, offset) => {
+                let size = self.eval(&size)?;
+                let offset = self.eval(&offset)?;
+                self.ops.align_patchable(size as usize, offset as usize);
+            },
+
+            Stmt::GlobalLabel(ident)  => self.ops.global_label(leak(&ident.node.name.as_str())),
+            Stmt::LocalLabel(ident)   => self.ops.local_label(leak(&ident.node.name.as_str())),
+            Stmt::DynamicLabel(expr)  => {
+                let label = self.dynamic_label(&expr)?;
+                self.ops.dynamic_label(label);
+            },
+
+            Stmt::GlobalJumpTarget(ident, size)   => self.ops.global_reloc(leak(&ident.node.name.as_str()), size.in_bytes()),
+            Stmt::ForwardJumpTarget(ident, size)  => self.ops.forward_reloc(leak(&ident.node.name.as_str()), size.in_bytes()),
+            Stmt::BackwardJumpTarget(ident, size) => self.ops.backward_reloc(leak(&ident.node.name.as_str()), size.in_bytes()),
+            Stmt::DynamicJumpTarget(expr, size)   => {
+                let label = self.dynamic_label(&expr)?;
+                self.ops.dynamic_reloc(label, size.in_bytes());
+            },
+
+            // patch points, call frame information and source locations have no meaning
+            // without a runtime to record them in
+            Stmt::JumpPatchPoint(_, _)      |
+            Stmt::ImmediatePatchPoint(_, _) |
+            Stmt::CfiStartProc              |
+            Stmt::CfiEndProc                |
+            Stmt::CfiDefCfa(_, _)           |
+            Stmt::CfiDefCfaOffset(_)        |
+            Stmt::CfiDefCfaRegister(_)      |
+            Stmt::CfiOffset(_, _)           |
+            Stmt::CfiRememberState          |
+            Stmt::CfiRestoreState           |
+            Stmt::SourceLocation(_, _)      |
+            Stmt::SourceLocationEnd         => (),
+
+            Stmt::Conditional(branches, otherwise) => {
+                for (condition, body) in branches {
+                    if self.eval_condition(&condition)? {
+                        return self.execute_all(body);
+                    }
+                }
+                return self.execute_all(otherwise);
+            },
+
+            Stmt::Stmt(stmt) => return Err((stmt.span, "Rust statements are not supported".into()))
+        }
+        Ok(())
+    }
+
+    fn execute_all(&mut self, stmts: Vec<Stmt>) -> EvalResult<()> {
+        for stmt in stmts {
+            self.execute(stmt)?;
+        }
+        Ok(())
+    }
+
+    // conditions are evaluated like integers in C, with logical operators short circuiting
+    fn eval_condition(&self, expr: &ast::Expr) -> EvalResult<bool> {
+        match expr.node {
+            ast::ExprKind::Paren(ref inner) => self.eval_condition(inner),
+            ast::ExprKind::Unary(ast::UnOp::Not, ref inner) => Ok(!self.eval_condition(inner)?),
+            ast::ExprKind::Binary(op, ref left, ref right) => match op.node {
+                ast::BinOpKind::And => Ok(self.eval_condition(left)? && self.eval_condition(right)?),
+                ast::BinOpKind::Or  => Ok(self.eval_condition(left)? || self.eval_condition(right)?),
+                ast::BinOpKind::Eq  => Ok(self.eval(left)? == self.eval(right)?),
+                ast::BinOpKind::Ne  => Ok(self.eval(left)? != self.eval(right)?),
+                ast::BinOpKind::Lt  => Ok(self.eval(left)? <  self.eval(right)?),
+                ast::BinOpKind::Le  => Ok(self.eval(left)? <= self.eval(right)?),
+                ast::BinOpKind::Gt  => Ok(self.eval(left)? >  self.eval(right)?),
+                ast::BinOpKind::Ge  => Ok(self.eval(left)? >= self.eval(right)?),
+                _ => Ok(self.eval(expr)? != 0)
+            },
+            _ => Ok(self.eval(expr)? != 0)
+        }
+    }
+
+    fn dynamic_label(&mut self, expr: &ast::Expr) -> EvalResult<DynamicLabel> {
+        let id = self.eval(expr)?;
+        let ops = &mut self.ops;
+        Ok(*self.dynamic_labels.entry(id).or_insert_with(|| ops.new_dynamic_label()))
+    }
+
+    fn eval(&self, expr: &ast::Expr) -> EvalResult<i64> {
+        match expr.node {
+            ast::ExprKind::Lit(ref lit) => match lit.node {
+                ast::LitKind::Int(value, _) => Ok(value as i64),
+                ast::LitKind::Byte(value)   => Ok(value as i64),
+                ast::LitKind::Char(value)   => Ok(value as i64),
+                ast::LitKind::Bool(value)   => Ok(value as i64),
+                _ => Err((expr.span, "only integer literals are supported".into()))
+            },
+            // casts are only used to pass values to the runtime, which truncates them anyway
+            ast::ExprKind::Paren(ref inner) |
+            ast::ExprKind::Cast(ref inner, _) => self.eval(inner),
+            ast::ExprKind::Unary(ast::UnOp::Neg, ref inner) => Ok(self.eval(inner)?.wrapping_neg()),
+            ast::ExprKind::Unary(ast::UnOp::Not, ref inner) => Ok(!self.eval(inner)?),
+            ast::ExprKind::Binary(op, ref left, ref right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                Ok(match op.node {
+                    ast::BinOpKind::Add    => left.wrapping_add(right),
+                    ast::BinOpKind::Sub    => left.wrapping_sub(right),
+                    ast::BinOpKind::Mul    => left.wrapping_mul(right),
+                    ast::BinOpKind::Div    |
+                    ast::BinOpKind::Rem    if right == 0 => return Err((expr.span, "division by zero".into())),
+                    ast::BinOpKind::Div    => left.wrapping_div(right),
+                    ast::BinOpKind::Rem    => left.wrapping_rem(right),
+                    ast::BinOpKind::BitAnd => left & right,
+                    ast::BinOpKind::BitOr  => left | right,
+                    ast::BinOpKind::BitXor => left ^ right,
+                    ast::BinOpKind::Shl    => left.wrapping_shl(right as u32),
+                    ast::BinOpKind::Shr    => left.wrapping_shr(right as u32),
+                    _ => return Err((op.span, "unsupported operator".into()))
+                })
+            },
+            ast::ExprKind::Path(None, ref path) if path.segments.len() == 1 => {
+                let name = path.segments[0].identifier.name.as_str();
+                match self.constants.get(&*name) {
+                    Some(&value) => Ok(value),
+                    None => Err((expr.span, format!("unknown constant '{}'", name)))
+                }
+            },
+            _ => Err((expr.span, "only integer literals and named constants are supported".into()))
+        }
+    }
+
+    // evaluates the values of .oword and .hword as u128, like rustc would. Literals are zero
+    // extended while constants, which are signed, are sign extended.
+    fn eval_wide(&self, expr: &ast::Expr) -> EvalResult<u128> {
+        match expr.node {
+            ast::ExprKind::Lit(ref lit) => match lit.node {
+                ast::LitKind::Int(value, _) => Ok(value as u128),
+                _ => Ok(self.eval(expr)? as u128)
+            },
+            ast::ExprKind::Paren(ref inner) |
+            ast::ExprKind::Cast(ref inner, _) => self.eval_wide(inner),
+            ast::ExprKind::Unary(ast::UnOp::Neg, ref inner) => Ok(self.eval_wide(inner)?.wrapping_neg()),
+            ast::ExprKind::Unary(ast::UnOp::Not, ref inner) => Ok(!self.eval_wide(inner)?),
+            ast::ExprKind::Binary(op, ref left, ref right) => {
+                let left = self.eval_wide(left)?;
+                let right = self.eval_wide(right)?;
+                Ok(match op.node {
+                    ast::BinOpKind::Add    => left.wrapping_add(right),
+                    ast::BinOpKind::Sub    => left.wrapping_sub(right),
+                    ast::BinOpKind::Mul    => left.wrapping_mul(right),
+                    ast::BinOpKind::Div    |
+                    ast::BinOpKind::Rem    if right == 0 => return Err((expr.span, "division by zero".into())),
+                    ast::BinOpKind::Div    => left / right,
+                    ast::BinOpKind::Rem    => left % right,
+                    ast::BinOpKind::BitAnd => left & right,
+                    ast::BinOpKind::BitOr  => left | right,
+                    ast::BinOpKind::BitXor => left ^ right,
+                    ast::BinOpKind::Shl    => left.wrapping_shl(right as u32),
+                    ast::BinOpKind::Shr    => left.wrapping_shr(right as u32),
+                    _ => return Err((op.span, "unsupported operator".into()))
+                })
+            },
+            _ => Ok(self.eval(expr)? as u128)
+        }
+    }
+
+    // floating point values are evaluated at double precision
+    fn eval_float(&self, expr: &ast::Expr) -> EvalResult<f64> {
+        match expr.node {
+            ast::ExprKind::Lit(ref lit) => match lit.node {
+                ast::LitKind::Float(ref value, _) |
+                ast::LitKind::FloatUnsuffixed(ref value) => match value.parse() {
+                    Ok(value) => Ok(value),
+                    Err(_) => Err((expr.span, "invalid floating point literal".into()))
+                },
+                _ => Ok(self.eval(expr)? as f64)
+            },
+            ast::ExprKind::Paren(ref inner) |
+            ast::ExprKind::Cast(ref inner, _) => self.eval_float(inner),
+            ast::ExprKind::Unary(ast::UnOp::Neg, ref inner) => Ok(-self.eval_float(inner)?),
+            ast::ExprKind::Binary(op, ref left, ref right) => {
+                let left = self.eval_float(left)?;
+                let right = self.eval_float(right)?;
+                Ok(match op.node {
+                    ast::BinOpKind::Add => left + right,
+                    ast::BinOpKind::Sub => left - right,
+                    ast::BinOpKind::Mul => left * right,
+                    ast::BinOpKind::Div => left / right,
+                    _ => return Err((op.span, "unsupported operator".into()))
+                })
+            },
+            _ => Ok(self.eval(expr)? as f64)
+        }
+    }
+}
+
+// the runtime takes label names as static strings. This process is short lived, so they're leaked.
+fn leak(name: &str) -> &'static str {
+    unsafe { &*Box::into_raw(name.to_string().into_boxed_str()) }
+}
@@ -20,6 +20,9 @@ mkdir ./build_docs/runtime
 # create instruction reference markdown file
 (cd doc/insref && cargo update && cargo run > ../instructionref.md)
 
+# export the instruction database for external tooling
+(cd doc/insref && cargo run -- --json > ../../build_docs/language/instructions.json)
+
 # build plugin docs
 for f in ./doc/*.md; do
     rustdoc $f -o ./build_docs/language --markdown-no-toc --html-before-content=./doc/pre.html --html-after-content=./doc/post.html --markdown-css=./formatting.css
@@ -7,17 +7,30 @@ extern crate dynasm;
 use dynasm::debug;
 use dynasm::x64data;
 
+use std::env;
 use std::io::{self, Write};
 use itertools::Itertools;
 
 fn main() {
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
-    stdout.write_all(b"% Instruction Reference\n\n").unwrap();
 
     let mut mnemnonics: Vec<_> = x64data::mnemnonics().cloned().collect();
     mnemnonics.sort();
 
+    // `--json` exports the instruction database for external tooling instead
+    if env::args().skip(1).any(|a| a == "--json") {
+        let entries = mnemnonics.into_iter()
+                                .map(|x| debug::format_opdata_json(x, x64data::get_mnemnonic_data(x).unwrap()))
+                                .collect::<Vec<_>>();
+        stdout.write_all(b"[\n").unwrap();
+        stdout.write_all(entries.join(",\n").as_bytes()).unwrap();
+        stdout.write_all(b"\n]\n").unwrap();
+        return;
+    }
+
+    stdout.write_all(b"% Instruction Reference\n\n").unwrap();
+
     for mnemnonic in mnemnonics {
         let data = x64data::get_mnemnonic_data(mnemnonic).unwrap();
         let mut formats = data.into_iter()
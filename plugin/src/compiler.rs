@@ -2,18 +2,16 @@ use syntax::ext::base::ExtCtxt;
 use syntax::ext::build::AstBuilder;
 use syntax::ast;
 use syntax::ptr::P;
-use syntax::codemap::Spanned;
+use syntax::parse::token;
+use syntax::codemap::{Span, Spanned};
 
 use parser::{self, Item, Arg, Ident, MemoryRef, Register, RegKind, RegFamily, RegId, Size, LabelType, JumpType};
-use x64data::get_mnemnonic_data;
+use x64data::{get_mnemnonic_data, Opdata, FormatStringIterator};
 use x64data::flags::*;
-use serialize::or_mask_shift_expr;
-use debug::format_opdata_list;
+use serialize::{or_mask_shift_expr, encodable_scale, scale_assertion};
+use debug::{format_opdata_list, format_stmts};
 
 use std::mem::swap;
-use std::slice;
-use std::iter;
-use std::collections::hash_map::Entry;
 
 /*
  * Compilation output
@@ -27,11 +25,16 @@ pub enum Stmt {
     ExprConst(P<ast::Expr>),
 
     Var(P<ast::Expr>, Size),
+    Float(P<ast::Expr>, Size),
     Extend(P<ast::Expr>),
+    // count, size and value of the elements to fill with
+    Fill(P<ast::Expr>, u8, P<ast::Expr>),
+    RuntimeError(Span, &'static str),
 
     DynScale(P<ast::Expr>, P<ast::Expr>),
 
     Align(P<ast::Expr>),
+    AlignPatchable(P<ast::Expr>, P<ast::Expr>),
 
     GlobalLabel(Ident),
     LocalLabel(Ident),
@@ -42,41 +45,25 @@ pub enum Stmt {
     BackwardJumpTarget(Ident, Size),
     DynamicJumpTarget(P<ast::Expr>, Size),
 
-    Stmt(ast::Stmt),
-}
+    JumpPatchPoint(P<ast::Expr>, Size),
+    ImmediatePatchPoint(P<ast::Expr>, Size),
 
-/*
- * Instruction encoding data formats
- */
+    CfiStartProc,
+    CfiEndProc,
+    CfiDefCfa(u8, P<ast::Expr>),
+    CfiDefCfaOffset(P<ast::Expr>),
+    CfiDefCfaRegister(u8),
+    CfiOffset(u8, P<ast::Expr>),
+    CfiRememberState,
+    CfiRestoreState,
 
-pub struct Opdata {
-    pub args:  &'static [u8],  // format string of arg format
-    pub ops:   &'static [u8],
-    pub reg:   u8,
-    pub flags: Flags
-}
-
-pub struct FormatStringIterator<'a> {
-    inner: iter::Cloned<slice::Iter<'a, u8>>
-}
-
-impl<'a> FormatStringIterator<'a> {
-    pub fn new(buf: &'a [u8]) -> FormatStringIterator<'a> {
-        FormatStringIterator {inner: buf.into_iter().cloned()}
-    }
-}
+    SourceLocation(Span, Ident),
+    SourceLocationEnd,
 
-impl<'a> Iterator for FormatStringIterator<'a> {
-    type Item = (u8, u8);
+    // the branches of a runtime conditional with their conditions, followed by the else branch
+    Conditional(Vec<(P<ast::Expr>, StmtBuffer)>, StmtBuffer),
 
-    fn next(&mut self) -> Option<(u8, u8)> {
-        if let Some(ty) = self.inner.next() {
-            let size = self.inner.next().expect("Invalid format string data");
-            Some((ty, size))
-        } else {
-            None
-        }
-    }
+    Stmt(ast::Stmt),
 }
 
 /*
@@ -93,59 +80,396 @@ const MOD_DISP32: u8 = 0b10;
  * Implmementation
  */
 
+// state kept while compiling a single dynasm! block
+struct CompileState {
+    successful: bool,
+    // patch point to be recorded for the next instruction
+    patch_point: Option<P<ast::Expr>>,
+    // whether the encoding of instructions should be shown, as enabled by .listing
+    listing: bool,
+    // whether instructions should report their source location, as enabled by .source_map
+    source_map: bool,
+    // open .if_cc and .while_cc blocks
+    structured: Vec<Structured>,
+    // the amount of those that were started, used to generate unique label names
+    structured_count: usize,
+    // the register that can be used to compute the addresses of type maps, as set by .typemap_scratch
+    typemap_scratch: Option<Register>
+}
+
+// a structured control flow block, with the number used in the names of its labels
+enum Structured {
+    // if an .else was seen
+    If(usize, bool),
+    While(usize)
+}
+
 pub fn compile(ecx: &ExtCtxt, nodes: Vec<parser::Item>) -> Result<StmtBuffer, ()>  {
     let mut stmts = StmtBuffer::new();
 
-    let mut successful = true;
+    let mut state = CompileState {
+        successful: true,
+        patch_point: None,
+        listing: false,
+        source_map: false,
+        structured: Vec::new(),
+        structured_count: 0,
+        typemap_scratch: None
+    };
+
+    compile_items(ecx, &mut state, &mut stmts, nodes);
+    check_patch_point(ecx, &mut state, ecx.call_site());
+
+    if !state.structured.is_empty() {
+        ecx.span_err(ecx.call_site(), "'.if_cc' or '.while_cc' without a matching '.endif' or '.endwhile'");
+        state.successful = false;
+    }
+
+    if state.successful {
+        Ok(stmts)
+    } else {
+        Err(())
+    }
+}
 
+fn compile_items(ecx: &ExtCtxt, state: &mut CompileState, stmts: &mut StmtBuffer, nodes: Vec<parser::Item>) {
     for node in nodes {
         match node {
             Item::Instruction(mut ops, args, span) => {
                 let op = ops.pop().unwrap();
-                match compile_op(ecx, &mut stmts, op, ops, args) {
-                    Ok(_) => (),
+                let mnemonic = op.clone();
+                let start = stmts.len();
+                let has_immediate = args.iter().any(|arg| match *arg {
+                    Arg::Immediate(..) => true,
+                    _ => false
+                });
+                match compile_typemapped_op(ecx, stmts, op, ops, args, state.typemap_scratch.as_ref()).and_then(|_| match state.patch_point.take() {
+                    Some(id) => compile_patch_point(ecx, stmts, start, id, has_immediate),
+                    None => Ok(())
+                }) {
+                    Ok(_) => {
+                        if state.listing {
+                            ecx.parse_sess.span_diagnostic.span_note_without_error(span, &format_stmts(&stmts[start..]));
+                        }
+                        if state.source_map {
+                            // the location is recorded after any padding inserted for a patch point
+                            let index = match stmts[start] {
+                                Stmt::AlignPatchable(_, _) => start + 1,
+                                _ => start
+                            };
+                            stmts.insert(index, Stmt::SourceLocation(span, mnemonic));
+                            stmts.push(Stmt::SourceLocationEnd);
+                        }
+                    },
                     Err(e) => {
-                        successful = false;
+                        state.successful = false;
                         if let Some(e) = e {
                             ecx.span_err(span, &e)
                         }
                     }
                 }
             },
-            Item::Label(label) => compile_label(&mut stmts, label),
-            Item::Directive(op, args, span) => {
-                match compile_directive(ecx, &mut stmts, op, args) {
+            Item::Label(label) => compile_label(stmts, label),
+            Item::Directive(op, mut args, span) => {
+                // patch points apply to the next instruction, so they're handled here
+                if &*op.node.name.as_str() == "patchpoint" {
+                    let arg = if args.len() == 1 {
+                        args.pop()
+                    } else {
+                        None
+                    };
+                    match arg {
+                        Some(Arg::Immediate(expr, None)) => state.patch_point = Some(expr),
+                        _ => {
+                            state.successful = false;
+                            ecx.span_err(span, "this directive requires a single immediate argument");
+                        }
+                    }
+                    continue;
+                }
+
+                // the scratch register applies to the rest of the block
+                if &*op.node.name.as_str() == "typemap_scratch" {
+                    let arg = if args.len() == 1 {
+                        args.pop()
+                    } else {
+                        None
+                    };
+                    match arg {
+                        Some(Arg::Direct(Spanned {node: reg, ..})) if reg.kind.family() == RegFamily::LEGACY => state.typemap_scratch = Some(reg),
+                        _ => {
+                            state.successful = false;
+                            ecx.span_err(span, "this directive requires a single general purpose register as argument");
+                        }
+                    }
+                    continue;
+                }
+
+                // source mapping applies to the rest of this dynasm! invocation
+                if &*op.node.name.as_str() == "source_map" {
+                    if args.is_empty() {
+                        state.source_map = true;
+                    } else {
+                        state.successful = false;
+                        ecx.span_err(span, "this directive does not take any arguments");
+                    }
+                    continue;
+                }
+
+                // the listing applies to the rest of this dynasm! invocation
+                if &*op.node.name.as_str() == "listing" {
+                    if args.is_empty() {
+                        state.listing = true;
+                    } else {
+                        state.successful = false;
+                        ecx.span_err(span, "this directive does not take any arguments");
+                    }
+                    continue;
+                }
+
+                let result = match &*op.node.name.as_str() {
+                    "if_cc" | "while_cc" | "else" | "endif" | "endwhile" => compile_structured(ecx, state, stmts, op, args),
+                    _ => compile_directive(ecx, stmts, op, args)
+                };
+                match result {
                     Ok(_) => (),
                     Err(e) => {
-                        successful = false;
+                        state.successful = false;
                         if let Some(e) = e {
                             ecx.span_err(span, &e)
                         }
                     }
                 }
             },
+            Item::Conditional(branches, otherwise, span) => {
+                // a patch point can't apply to an instruction in a conditional, and structured
+                // control flow can't cross its branches
+                check_patch_point(ecx, state, span);
+                let depth = state.structured.len();
+
+                let mut compiled = Vec::new();
+                for (condition, items) in branches {
+                    let mut buffer = StmtBuffer::new();
+                    compile_items(ecx, state, &mut buffer, items);
+                    check_patch_point(ecx, state, span);
+                    check_structured(ecx, state, depth, span);
+                    compiled.push((condition, buffer));
+                }
+
+                let mut buffer = StmtBuffer::new();
+                compile_items(ecx, state, &mut buffer, otherwise);
+                check_patch_point(ecx, state, span);
+                check_structured(ecx, state, depth, span);
+
+                stmts.push(Stmt::Conditional(compiled, buffer));
+            },
             Item::Stmt(stmt) => {
                 stmts.push(Stmt::Stmt(stmt));
             }
         }
     }
+}
 
-    if successful {
-        Ok(stmts)
-    } else {
-        Err(())
+// structured control flow is compiled to conditional jumps to local labels. An .if_cc jumps
+// to the else label if the condition doesn't hold, while a .while_cc checks the condition at the
+// start of every iteration and jumps back there at the end of the body.
+fn compile_structured(ecx: &ExtCtxt, state: &mut CompileState, stmts: &mut StmtBuffer, op: Ident, mut args: Vec<Arg>) -> Result<(), Option<String>> {
+    let span = op.span;
+    let name = op.node.name.as_str();
+
+    if &*name == "if_cc" || &*name == "while_cc" {
+        if args.len() != 1 {
+            return Err(Some("Invalid amount of arguments".into()));
+        }
+        let inverse = match args.pop().unwrap() {
+            Arg::Immediate(expr, None) => parser::as_simple_name(&expr).and_then(|cc| inverse_condition(&cc.node.name.as_str())),
+            _ => None
+        };
+        let inverse = if let Some(inverse) = inverse {
+            inverse
+        } else {
+            return Err(Some("The argument to this directive should be a condition code".into()));
+        };
+
+        let id = state.structured_count;
+        state.structured_count += 1;
+        if &*name == "if_cc" {
+            try!(compile_jump(ecx, stmts, span, &format!("j{}", inverse), JumpType::Forward(structured_label(span, "else", id))));
+            state.structured.push(Structured::If(id, false));
+        } else {
+            compile_label(stmts, LabelType::Local(structured_label(span, "while", id)));
+            try!(compile_jump(ecx, stmts, span, &format!("j{}", inverse), JumpType::Forward(structured_label(span, "endwhile", id))));
+            state.structured.push(Structured::While(id));
+        }
+        return Ok(());
+    }
+
+    if !args.is_empty() {
+        return Err(Some("this directive does not take any arguments".into()));
+    }
+
+    match &*name {
+        "else" => {
+            let id = match state.structured.last_mut() {
+                Some(&mut Structured::If(id, ref mut has_else)) => {
+                    if *has_else {
+                        return Err(Some("Duplicate '.else' in '.if_cc'".into()));
+                    }
+                    *has_else = true;
+                    id
+                },
+                _ => return Err(Some("'.else' without a matching '.if_cc'".into()))
+            };
+            try!(compile_jump(ecx, stmts, span, "jmp", JumpType::Forward(structured_label(span, "endif", id))));
+            compile_label(stmts, LabelType::Local(structured_label(span, "else", id)));
+        },
+        "endif" => match state.structured.pop() {
+            Some(Structured::If(id, has_else)) => {
+                let label = if has_else { "endif" } else { "else" };
+                compile_label(stmts, LabelType::Local(structured_label(span, label, id)));
+            },
+            other => {
+                state.structured.extend(other);
+                return Err(Some("'.endif' without a matching '.if_cc'".into()));
+            }
+        },
+        "endwhile" => match state.structured.pop() {
+            Some(Structured::While(id)) => {
+                try!(compile_jump(ecx, stmts, span, "jmp", JumpType::Backward(structured_label(span, "while", id))));
+                compile_label(stmts, LabelType::Local(structured_label(span, "endwhile", id)));
+            },
+            other => {
+                state.structured.extend(other);
+                return Err(Some("'.endwhile' without a matching '.while_cc'".into()));
+            }
+        },
+        _ => unreachable!()
+    }
+    Ok(())
+}
+
+fn compile_jump(ecx: &ExtCtxt, stmts: &mut StmtBuffer, span: Span, mnemonic: &str, target: JumpType) -> Result<(), Option<String>> {
+    let op = Spanned {node: token::str_to_ident(mnemonic), span: span};
+    compile_op(ecx, stmts, op, Vec::new(), vec![Arg::JumpTarget(target, None)], true)
+}
+
+// labels are passed to the runtime as strings, so the '#' in these names guarantees that they
+// can't be written as an identifier by the user or be produced by renaming labels in macros.
+fn structured_label(span: Span, kind: &str, id: usize) -> Ident {
+    Spanned {node: token::str_to_ident(&format!("{}#{}", kind, id)), span: span}
+}
+
+// returns the condition code that holds exactly when `cc` doesn't
+fn inverse_condition(cc: &str) -> Option<&'static str> {
+    Some(match cc {
+        "o"                 => "no",
+        "no"                => "o",
+        "b"  | "c"  | "nae" => "nb",
+        "nb" | "nc" | "ae"  => "b",
+        "e"  | "z"          => "ne",
+        "ne" | "nz"         => "e",
+        "be" | "na"         => "nbe",
+        "nbe"| "a"          => "be",
+        "s"                 => "ns",
+        "ns"                => "s",
+        "p"  | "pe"         => "np",
+        "np" | "po"         => "p",
+        "l"  | "nge"        => "nl",
+        "nl" | "ge"         => "l",
+        "le" | "ng"         => "nle",
+        "nle"| "g"          => "le",
+        _ => return None
+    })
+}
+
+fn check_structured(ecx: &ExtCtxt, state: &mut CompileState, depth: usize, span: Span) {
+    if state.structured.len() != depth {
+        ecx.span_err(span, "'.if_cc' and '.while_cc' blocks must end in the branch of the conditional they started in");
+        state.successful = false;
+        state.structured.truncate(depth);
+    }
+}
+
+fn check_patch_point(ecx: &ExtCtxt, state: &mut CompileState, span: Span) {
+    if state.patch_point.take().is_some() {
+        ecx.span_err(span, "patch point directive without a following instruction");
+        state.successful = false;
     }
 }
 
 fn compile_directive(ecx: &ExtCtxt, buffer: &mut StmtBuffer, dir: Ident, mut args: Vec<Arg>) -> Result<(), Option<String>> {
     match &*dir.node.name.as_str() {
-        // TODO: oword, qword, float, double, long double
-        // TODO: iterators <- gives us strings and bytestrings for free
         "byte"  => directive_const(ecx, buffer, args, Size::BYTE),
         "word"  => directive_const(ecx, buffer, args, Size::WORD),
         "dword" => directive_const(ecx, buffer, args, Size::DWORD),
         "qword" => directive_const(ecx, buffer, args, Size::QWORD),
+        "oword" => directive_const(ecx, buffer, args, Size::OWORD),
+        "hword" => directive_const(ecx, buffer, args, Size::HWORD),
+        "float" => directive_float(ecx, buffer, args, Size::DWORD),
+        "double" => directive_float(ecx, buffer, args, Size::QWORD),
+        "tfloat" => directive_float(ecx, buffer, args, Size::PWORD),
         "bytes" => directive_iter(ecx, buffer, args),
+        "ascii" => directive_string(ecx, buffer, args, false),
+        "asciz" => directive_string(ecx, buffer, args, true),
+        "utf16" => directive_utf16(ecx, buffer, args),
+        "space" => {
+            if args.len() != 1 && args.len() != 2 {
+                return Err(Some("Invalid amount of arguments".into()));
+            }
+
+            let mut exprs = Vec::new();
+            for arg in args {
+                match arg {
+                    Arg::Immediate(expr, None) => exprs.push(expr),
+                    _ => return Err(Some("this directive only uses immediate arguments".into()))
+                }
+            }
+
+            let mut exprs = exprs.into_iter();
+            let count = exprs.next().unwrap();
+            let value = exprs.next().unwrap_or_else(|| ecx.expr_u8(dir.span, 0));
+            buffer.push(Stmt::Fill(count, 1, value));
+            Ok(())
+        },
+        "fill" => {
+            if args.is_empty() || args.len() > 3 {
+                return Err(Some("Invalid amount of arguments".into()));
+            }
+
+            let mut exprs = Vec::new();
+            for arg in args {
+                match arg {
+                    Arg::Immediate(expr, None) => exprs.push(expr),
+                    _ => return Err(Some("this directive only uses immediate arguments".into()))
+                }
+            }
+
+            let mut exprs = exprs.into_iter();
+            let count = exprs.next().unwrap();
+            // the size determines the generated code, so it has to be known
+            let size = match exprs.next() {
+                None => 1,
+                Some(expr) => match expr.node {
+                    ast::ExprKind::Lit(ref lit) => match lit.node {
+                        ast::LitKind::Int(size @ 1, _) |
+                        ast::LitKind::Int(size @ 2, _) |
+                        ast::LitKind::Int(size @ 4, _) |
+                        ast::LitKind::Int(size @ 8, _) => size as u8,
+                        _ => {
+                            ecx.span_err(expr.span, "the size should be 1, 2, 4 or 8");
+                            return Err(None);
+                        }
+                    },
+                    _ => {
+                        ecx.span_err(expr.span, "the size should be an integer literal");
+                        return Err(None);
+                    }
+                }
+            };
+            let value = exprs.next().unwrap_or_else(|| ecx.expr_u8(dir.span, 0));
+            buffer.push(Stmt::Fill(count, size, value));
+            Ok(())
+        },
         "align" => {
             if args.len() != 1 {
                 return Err(Some("Invalid amount of arguments".into()));
@@ -159,35 +483,61 @@ fn compile_directive(ecx: &ExtCtxt, buffer: &mut StmtBuffer, dir: Ident, mut arg
             }
             Ok(())
         },
-        "alias" => {
-            if args.len() != 2 {
+        "patchable" => {
+            if args.len() != 1 && args.len() != 2 {
                 return Err(Some("Invalid amount of arguments".into()));
             }
 
-            let reg = match args.pop().unwrap() {
-                Arg::Direct(Spanned {node: Register {kind: RegKind::Static(id), size}, ..}) => (id, size),
-                _ => {
-                    return Err(Some("The second argument to alias should be a static register".into()));
+            let mut exprs = Vec::new();
+            for arg in args {
+                match arg {
+                    Arg::Immediate(expr, None) => exprs.push(expr),
+                    _ => return Err(Some("this directive only uses immediate arguments".into()))
                 }
-            };
-
-            let alias = match args.pop().unwrap() {
-                Arg::Immediate(expr, _) => parser::as_simple_name(&*expr),
-                _ => None
-            };
-
-            let alias = if let Some(alias) = alias {
-                alias.node.name
-            } else {
-                return Err(Some("The first argument to alias should be a non-keyword immediate".into()));
-            };
+            }
 
-            let global_data = super::crate_local_data(ecx);
-            let mut lock = global_data.write();
-            match lock.aliases.entry(alias) {
-                Entry::Occupied(_) => return Err(Some(format!("Duplicate alias definition, alias '{}' was earlier defined", alias.as_str()))),
-                Entry::Vacant(v) => v.insert(reg)
-            };
+            let mut exprs = exprs.into_iter();
+            let size = exprs.next().unwrap();
+            let offset = exprs.next().unwrap_or_else(|| ecx.expr_usize(dir.span, 0));
+            buffer.push(Stmt::AlignPatchable(size, offset));
+            Ok(())
+        },
+        "cfi_startproc"      => directive_cfi(buffer, args, Stmt::CfiStartProc),
+        "cfi_endproc"        => directive_cfi(buffer, args, Stmt::CfiEndProc),
+        "cfi_remember_state" => directive_cfi(buffer, args, Stmt::CfiRememberState),
+        "cfi_restore_state"  => directive_cfi(buffer, args, Stmt::CfiRestoreState),
+        "cfi_def_cfa" => {
+            if args.len() != 2 {
+                return Err(Some("Invalid amount of arguments".into()));
+            }
+            let offset = try!(cfi_offset_arg(args.pop().unwrap()));
+            let reg = try!(cfi_register_arg(args.pop().unwrap()));
+            buffer.push(Stmt::CfiDefCfa(reg, offset));
+            Ok(())
+        },
+        "cfi_def_cfa_offset" => {
+            if args.len() != 1 {
+                return Err(Some("Invalid amount of arguments".into()));
+            }
+            let offset = try!(cfi_offset_arg(args.pop().unwrap()));
+            buffer.push(Stmt::CfiDefCfaOffset(offset));
+            Ok(())
+        },
+        "cfi_def_cfa_register" => {
+            if args.len() != 1 {
+                return Err(Some("Invalid amount of arguments".into()));
+            }
+            let reg = try!(cfi_register_arg(args.pop().unwrap()));
+            buffer.push(Stmt::CfiDefCfaRegister(reg));
+            Ok(())
+        },
+        "cfi_offset" => {
+            if args.len() != 2 {
+                return Err(Some("Invalid amount of arguments".into()));
+            }
+            let offset = try!(cfi_offset_arg(args.pop().unwrap()));
+            let reg = try!(cfi_register_arg(args.pop().unwrap()));
+            buffer.push(Stmt::CfiOffset(reg, offset));
             Ok(())
         },
         d => {
@@ -197,6 +547,38 @@ fn compile_directive(ecx: &ExtCtxt, buffer: &mut StmtBuffer, dir: Ident, mut arg
     }
 }
 
+fn directive_cfi(buffer: &mut StmtBuffer, args: Vec<Arg>, stmt: Stmt) -> Result<(), Option<String>> {
+    if !args.is_empty() {
+        return Err(Some("this directive does not take any arguments".into()));
+    }
+    buffer.push(stmt);
+    Ok(())
+}
+
+fn cfi_offset_arg(arg: Arg) -> Result<P<ast::Expr>, Option<String>> {
+    match arg {
+        Arg::Immediate(expr, None) => Ok(expr),
+        _ => Err(Some("expected an immediate offset".into()))
+    }
+}
+
+// call frame information refers to registers by their DWARF register number
+fn cfi_register_arg(arg: Arg) -> Result<u8, Option<String>> {
+    let id = match arg {
+        Arg::Direct(Spanned {node: Register {kind: RegKind::Static(id), size: Size::QWORD}, ..}) => id,
+        Arg::Direct(Spanned {node: Register {kind: RegKind::Static(id), size: Size::OWORD}, ..}) => id,
+        _ => return Err(Some("expected a static 64-bit general purpose register or an xmm register".into()))
+    };
+
+    const LEGACY: [u8; 16] = [0, 2, 1, 3, 7, 6, 4, 5, 8, 9, 10, 11, 12, 13, 14, 15];
+    match id.family() {
+        RegFamily::LEGACY => Ok(LEGACY[id.code() as usize]),
+        RegFamily::RIP => Ok(16),
+        RegFamily::XMM => Ok(17 + id.code()),
+        _ => Err(Some("this register cannot be described in call frame information".into()))
+    }
+}
+
 fn directive_const(ecx: &ExtCtxt, buffer: &mut StmtBuffer, args: Vec<Arg>, size: Size) -> Result<(), Option<String>> {
     if args.is_empty() {
         return Err(Some("this directive requires at least one argument".into()));
@@ -218,6 +600,96 @@ fn directive_const(ecx: &ExtCtxt, buffer: &mut StmtBuffer, args: Vec<Arg>, size:
     Ok(())
 }
 
+fn directive_float(ecx: &ExtCtxt, buffer: &mut StmtBuffer, args: Vec<Arg>, size: Size) -> Result<(), Option<String>> {
+    if args.is_empty() {
+        return Err(Some("this directive requires at least one argument".into()));
+    }
+
+    for arg in args {
+        match arg {
+            Arg::Immediate(expr, None) => buffer.push(Stmt::Float(expr, size)),
+            Arg::Immediate(expr, Some(_)) => {
+                ecx.span_err(expr.span, "floating point values cannot be given a size");
+                return Err(None)
+            },
+            _ => return Err(Some("this directive only uses immediate arguments".into()))
+        }
+    }
+
+    Ok(())
+}
+
+// string literals are encoded at compile time, other strings are copied in at runtime
+fn directive_string(ecx: &ExtCtxt, buffer: &mut StmtBuffer, args: Vec<Arg>, terminated: bool) -> Result<(), Option<String>> {
+    if args.is_empty() {
+        return Err(Some("this directive requires at least one argument".into()));
+    }
+
+    for arg in args {
+        let expr = match arg {
+            Arg::Immediate(expr, None) => expr,
+            _ => return Err(Some("this directive only uses immediate arguments".into()))
+        };
+
+        match string_literal(&expr) {
+            Some(bytes) => buffer.extend(bytes.into_iter().map(Stmt::Const)),
+            None => {
+                let span = expr.span;
+                buffer.push(Stmt::Extend(ecx.expr_method_call(span, expr, ast::Ident::with_empty_ctxt(token::intern("bytes")), Vec::new())));
+            }
+        }
+        if terminated {
+            buffer.push(Stmt::Const(0));
+        }
+    }
+
+    Ok(())
+}
+
+fn directive_utf16(ecx: &ExtCtxt, buffer: &mut StmtBuffer, args: Vec<Arg>) -> Result<(), Option<String>> {
+    if args.is_empty() {
+        return Err(Some("this directive requires at least one argument".into()));
+    }
+
+    for arg in args {
+        let expr = match arg {
+            Arg::Immediate(expr, None) => expr,
+            _ => return Err(Some("this directive only uses immediate arguments".into()))
+        };
+
+        let string = match expr.node {
+            ast::ExprKind::Lit(ref lit) => match lit.node {
+                ast::LitKind::Str(ref string, _) => Some(string.to_string()),
+                _ => None
+            },
+            _ => None
+        };
+        match string {
+            Some(string) => for unit in string.encode_utf16() {
+                buffer.push(Stmt::Const(unit as u8));
+                buffer.push(Stmt::Const((unit >> 8) as u8));
+            },
+            None => {
+                ecx.span_err(expr.span, "this directive only accepts string literals");
+                return Err(None);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn string_literal(expr: &ast::Expr) -> Option<Vec<u8>> {
+    if let ast::ExprKind::Lit(ref lit) = expr.node {
+        match lit.node {
+            ast::LitKind::Str(ref string, _) => return Some(string.as_bytes().to_vec()),
+            ast::LitKind::ByteStr(ref bytes) => return Some((**bytes).clone()),
+            _ => ()
+        }
+    }
+    None
+}
+
 fn directive_iter(_ecx: &ExtCtxt, buffer: &mut StmtBuffer, mut args: Vec<Arg>) -> Result<(), Option<String>> {
     if args.len() != 1 {
         return Err(Some("Wrong amount of arguments for this directive".into()))
@@ -231,6 +703,39 @@ fn directive_iter(_ecx: &ExtCtxt, buffer: &mut StmtBuffer, mut args: Vec<Arg>) -
     Ok(())
 }
 
+fn compile_patch_point(ecx: &ExtCtxt, buffer: &mut StmtBuffer, start: usize, id: P<ast::Expr>, has_immediate: bool) -> Result<(), Option<String>> {
+    // the patch point refers to the last field of the instruction, which has to be a jump target or an immediate.
+    // immediates are encoded after any displacement, so without an immediate the last field is a displacement.
+    let (stmt, size) = match buffer.last() {
+        Some(&Stmt::GlobalJumpTarget(_, size))     |
+        Some(&Stmt::ForwardJumpTarget(_, size))    |
+        Some(&Stmt::BackwardJumpTarget(_, size))   |
+        Some(&Stmt::DynamicJumpTarget(_, size))    => (Stmt::JumpPatchPoint(id, size), size),
+        Some(&Stmt::Var(_, size)) if has_immediate => (Stmt::ImmediatePatchPoint(id, size), size),
+        Some(&Stmt::Var(_, _))                     => return Err(Some("Patch points cannot refer to the displacement of a memory reference".into())),
+        _ => return Err(Some("Patch points can only be used with instructions that end with an immediate or a jump target".into()))
+    };
+
+    if size.in_bytes() > 8 {
+        return Err(Some("Patch points can only refer to fields of at most 8 bytes".into()));
+    }
+
+    // determine the length of the instruction so it can be padded to make the field atomically writable.
+    let len = buffer[start..].iter().map(|stmt| match *stmt {
+        Stmt::Const(_)        |
+        Stmt::ExprConst(_)    |
+        Stmt::DynScale(_, _)  => 1,
+        Stmt::Var(_, size)    => size.in_bytes() as usize,
+        _                     => 0
+    }).sum::<usize>();
+
+    let span = ecx.call_site();
+    let offset = len - size.in_bytes() as usize;
+    buffer.insert(start, Stmt::AlignPatchable(ecx.expr_usize(span, size.in_bytes() as usize), ecx.expr_usize(span, offset)));
+    buffer.push(stmt);
+    Ok(())
+}
+
 fn compile_label(stmts: &mut StmtBuffer, label: LabelType) {
     stmts.push(match label {
         LabelType::Global(ident) => Stmt::GlobalLabel(ident),
@@ -239,15 +744,202 @@ fn compile_label(stmts: &mut StmtBuffer, label: LabelType) {
     });
 }
 
-fn compile_op(ecx: &ExtCtxt, buffer: &mut StmtBuffer, op: Ident, prefixes: Vec<Ident>, mut args: Vec<Arg>) -> Result<(), Option<String>> {
+// type maps with an element size that can't be encoded as a scale need a scratch register to
+// compute the address in. If the size is only known at runtime, code for both cases is generated.
+fn compile_typemapped_op(ecx: &ExtCtxt, buffer: &mut StmtBuffer, op: Ident, prefixes: Vec<Ident>, args: Vec<Arg>, scratch: Option<&Register>) -> Result<(), Option<String>> {
+    let scale = args.iter().filter_map(|arg| match *arg {
+        Arg::Indirect(MemoryRef {scale_expr: Some(ref expr), ..}) => Some(expr.clone()),
+        _ => None
+    }).next();
+    let scale = if let Some(scale) = scale {
+        scale
+    } else {
+        return compile_inferred_op(ecx, buffer, op, prefixes, args);
+    };
+
+    let static_scale = match scale.node {
+        ast::ExprKind::Lit(ref lit) => match lit.node {
+            ast::LitKind::Int(value, _) => Some(value),
+            _ => None
+        },
+        _ => None
+    };
+
+    match (static_scale, scratch) {
+        (Some(size), None) => Err(Some(format!("Type size {} cannot be encoded as a scale, use '.typemap_scratch' to provide a register to compute the address in", size))),
+        (Some(_), Some(scratch)) => compile_scratch_scaled_op(ecx, buffer, op, prefixes, args, scratch),
+        (None, Some(scratch)) => {
+            let mut encodable = StmtBuffer::new();
+            try!(compile_inferred_op(ecx, &mut encodable, op, prefixes.clone(), args.clone()));
+            let mut fallback = StmtBuffer::new();
+            try!(compile_scratch_scaled_op(ecx, &mut fallback, op, prefixes, args, scratch));
+
+            let condition = encodable_scale(ecx, op.span, scale);
+            buffer.push(Stmt::Conditional(vec![(condition, encodable)], fallback));
+            Ok(())
+        },
+        (None, None) => {
+            // without a scratch register, a type whose size can't be encoded as a scale is rejected
+            // when the generated code is compiled.
+            buffer.push(Stmt::Stmt(scale_assertion(ecx, op.span, scale)));
+            compile_inferred_op(ecx, buffer, op, prefixes, args)
+        }
+    }
+}
+
+// multiplies the index by the element size into the scratch register with imul, and uses the
+// result as unscaled index instead.
+fn compile_scratch_scaled_op(ecx: &ExtCtxt, buffer: &mut StmtBuffer, op: Ident, prefixes: Vec<Ident>, mut args: Vec<Arg>, scratch: &Register) -> Result<(), Option<String>> {
+    let span = op.span;
+
+    let mut product = None;
+    for arg in &mut args {
+        if let Arg::Indirect(ref mut mem) = *arg {
+            if let Some(scale) = mem.scale_expr.take() {
+                // the scratch register is overwritten before the memory reference is used
+                if let RegKind::Static(scratch_id) = scratch.kind {
+                    let conflict = mem.base.iter().chain(mem.index.iter()).any(|reg| match reg.kind {
+                        RegKind::Static(id) => id == scratch_id,
+                        RegKind::Dynamic(..) => false
+                    });
+                    if conflict {
+                        return Err(Some("The type map scratch register cannot be used as base or index of the same memory reference".into()));
+                    }
+                }
+                let index = mem.index.take().expect("type map with a scale but without an index");
+                let temp = Register {size: index.size(), kind: scratch.kind.clone()};
+                mem.index = Some(temp.clone());
+                mem.scale = 1;
+                product = Some((temp, index, scale));
+            }
+        }
+    }
+    let (temp, index, scale) = product.unwrap();
+
+    // sizes known at compile time that are products of the factors lea can multiply by are
+    // computed using lea, as it leaves the flags alone. Otherwise imul has to be used.
+    let factors = match scale.node {
+        ast::ExprKind::Lit(ref lit) => match lit.node {
+            ast::LitKind::Int(value, _) => lea_factors(value),
+            _ => None
+        },
+        _ => None
+    };
+
+    if let Some(factors) = factors {
+        // lea scratch, [index * factor], followed by lea scratch, [scratch * factor]
+        let mut source = index;
+        for factor in factors {
+            try!(compile_op(ecx, buffer, Spanned {node: token::str_to_ident("lea"), span: span}, Vec::new(), vec![
+                Arg::Direct(Spanned {node: temp.clone(), span: span}),
+                Arg::Indirect(MemoryRef {
+                    index:      Some(source),
+                    scale:      factor as isize,
+                    scale_expr: None,
+                    base:       None,
+                    disp:       None,
+                    size:       None,
+                    size_expr:  None,
+                    span:       span
+                })
+            ], true));
+            source = temp.clone();
+        }
+    } else {
+        // imul scratch, index, DWORD scale as i32
+        let i32_id = ast::Ident::with_empty_ctxt(token::intern("i32"));
+        let scale = ecx.expr_cast(span, scale, ecx.ty_ident(span, i32_id));
+        try!(compile_op(ecx, buffer, Spanned {node: token::str_to_ident("imul"), span: span}, Vec::new(), vec![
+            Arg::Direct(Spanned {node: temp, span: span}),
+            Arg::Direct(Spanned {node: index, span: span}),
+            Arg::Immediate(scale, Some(Size::DWORD))
+        ], true));
+    }
+
+    compile_inferred_op(ecx, buffer, op, prefixes, args)
+}
+
+// splits size into factors that lea can multiply by, either as [index * factor] or as
+// [index + index * (factor - 1)]. Returns None if this is not possible.
+fn lea_factors(mut size: u64) -> Option<Vec<u64>> {
+    let mut factors = Vec::new();
+    while size > 1 {
+        match [9, 8, 5, 4, 3, 2].iter().cloned().find(|&factor| size % factor == 0) {
+            Some(factor) => {
+                factors.push(factor);
+                size /= factor;
+            },
+            None => return None
+        }
+    }
+    if factors.is_empty() {
+        None
+    } else {
+        Some(factors)
+    }
+}
+
+// the size of a type mapped memory reference without a size hint is only determined by the type
+// of the field it refers to if the instruction can't be compiled otherwise. In that case, the
+// instruction is compiled for every size the field could have, and the right one is chosen at runtime.
+fn compile_inferred_op(ecx: &ExtCtxt, buffer: &mut StmtBuffer, op: Ident, prefixes: Vec<Ident>, args: Vec<Arg>) -> Result<(), Option<String>> {
+    let size_expr = args.iter().filter_map(|arg| match *arg {
+        Arg::Indirect(MemoryRef {size: None, size_expr: Some(ref expr), ..}) => Some(expr.clone()),
+        _ => None
+    }).next();
+    let size_expr = if let Some(size_expr) = size_expr {
+        size_expr
+    } else {
+        return compile_op(ecx, buffer, op, prefixes, args, true);
+    };
+
+    let mut stmts = StmtBuffer::new();
+    let error = match compile_op(ecx, &mut stmts, op, prefixes.clone(), args.clone(), true) {
+        Ok(()) => {
+            buffer.extend(stmts);
+            return Ok(());
+        },
+        Err(Some(e)) => e,
+        Err(None) => return Err(None)
+    };
+
+    let span = op.span;
+    let mut branches = Vec::new();
+    for &size in &[Size::BYTE, Size::WORD, Size::DWORD, Size::QWORD] {
+        let mut args = args.clone();
+        for arg in &mut args {
+            if let Arg::Indirect(ref mut mem) = *arg {
+                if mem.size.is_none() && mem.size_expr.is_some() {
+                    mem.size = Some(size);
+                }
+            }
+        }
+
+        // sizes that don't work are simply skipped, so this doesn't report any errors
+        let mut stmts = StmtBuffer::new();
+        if compile_op(ecx, &mut stmts, op, prefixes.clone(), args, false).is_ok() {
+            let condition = ecx.expr_binary(span, ast::BinOpKind::Eq, size_expr.clone(), ecx.expr_usize(span, size.in_bytes() as usize));
+            branches.push((condition, stmts));
+        }
+    }
+
+    if branches.is_empty() {
+        return Err(Some(error));
+    }
+    buffer.push(Stmt::Conditional(branches, vec![Stmt::RuntimeError(span, "The size of this type mapped field cannot be used with this instruction")]));
+    Ok(())
+}
+
+// compiles a single instruction. Errors that are not returned are only reported if report is set.
+fn compile_op(ecx: &ExtCtxt, buffer: &mut StmtBuffer, op: Ident, prefixes: Vec<Ident>, mut args: Vec<Arg>, report: bool) -> Result<(), Option<String>> {
     // sanitize memory references and determine address size
-    let pref_addr = try!(sanitize_addresses(ecx, &mut args));
+    let pref_addr = try!(sanitize_addresses(ecx, &mut args, report));
 
     // this call also inserts more size information in the AST if applicable.
-    let data = try!(match_op_format(ecx, op, &mut args));
+    let data = try!(match_op_format(ecx, op, &mut args, report));
 
     // determine legacy prefixes
-    let (mut pref_mod, pref_seg) = try!(get_legacy_prefixes(ecx, data, prefixes));
+    let (mut pref_mod, pref_seg) = try!(get_legacy_prefixes(ecx, data, prefixes, report));
 
     let mut op_size = Size::BYTE; // unused value, just here to please the compiler
     let mut pref_size = false;
@@ -530,17 +1222,19 @@ fn compile_op(ecx: &ExtCtxt, buffer: &mut StmtBuffer, op: Ident, prefixes: Vec<I
     Ok(())
 }
 
-fn sanitize_addresses(ecx: &ExtCtxt, args: &mut [Arg]) -> Result<bool, Option<String>> {
+fn sanitize_addresses(ecx: &ExtCtxt, args: &mut [Arg], report: bool) -> Result<bool, Option<String>> {
     // determine if an address size prefix is necessary, and sanitize the register choice for memoryrefs
     let mut addr_size = None;
     for arg in args {
         if let Arg::Indirect(ref mut mem) = *arg {
-            try!(sanitize_memoryref(ecx, mem));
+            try!(sanitize_memoryref(ecx, mem, report));
 
             if let Some(ref reg) = mem.base {
                 if reg.kind.family() == RegFamily::LEGACY || reg.kind.family() == RegFamily::RIP {
                     if addr_size.is_some() && addr_size != Some(reg.size()) {
-                        ecx.span_err(mem.span, "Conflicting address sizes");
+                        if report {
+                            ecx.span_err(mem.span, "Conflicting address sizes");
+                        }
                         return Err(None);
                     }
                     addr_size = Some(reg.size());
@@ -549,7 +1243,9 @@ fn sanitize_addresses(ecx: &ExtCtxt, args: &mut [Arg]) -> Result<bool, Option<St
             if let Some(ref reg) = mem.index {
                 if reg.kind.family() == RegFamily::LEGACY || reg.kind.family() == RegFamily::RIP {
                     if addr_size.is_some() && addr_size != Some(reg.size()) {
-                        ecx.span_err(mem.span, "Conflicting address sizes");
+                        if report {
+                            ecx.span_err(mem.span, "Conflicting address sizes");
+                        }
                         return Err(None);
                     }
                     addr_size = Some(reg.size());
@@ -565,7 +1261,7 @@ fn sanitize_addresses(ecx: &ExtCtxt, args: &mut [Arg]) -> Result<bool, Option<St
     Ok(addr_size != Size::QWORD)
 }
 
-fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<String>> {
+fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef, report: bool) -> Result<(), Option<String>> {
     // sort out impossible scales
     if let Some(ref index) = mem.index {
         mem.scale = match (mem.scale, mem.base.is_none()) {
@@ -591,7 +1287,9 @@ fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<S
                 3
             },
             (scale, _) => {
-                ecx.span_err(mem.span, &format!("Scale '{}' cannot be encoded", scale));
+                if report {
+                    ecx.span_err(mem.span, &format!("Scale '{}' cannot be encoded", scale));
+                }
                 return Err(None);
             }
         };
@@ -610,7 +1308,9 @@ fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<S
                 return Ok(());
             },
             _ => {
-                ecx.span_err(mem.span, "vsib addressing requires a general purpose register as base");
+                if report {
+                    ecx.span_err(mem.span, "vsib addressing requires a general purpose register as base");
+                }
                 return Err(None);
             }
         },
@@ -619,7 +1319,9 @@ fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<S
                 if base.as_ref().map_or(true, |x| x.kind.family() == RegFamily::LEGACY) {
                     return Ok(());
                 } else {
-                    ecx.span_err(mem.span, "vsib addressing requires a general purpose register as base");
+                    if report {
+                        ecx.span_err(mem.span, "vsib addressing requires a general purpose register as base");
+                    }
                     return Err(None);
                 }
             },
@@ -629,16 +1331,22 @@ fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<S
 
     // check that only legacy regs / rip are used:
     if mem.base.as_ref().map_or(false, |x| x.kind.family() != RegFamily::LEGACY && x.kind != RegId::RIP) {
-        ecx.span_err(mem.span, "bad register type as base");
+        if report {
+            ecx.span_err(mem.span, "bad register type as base");
+        }
         return Err(None);
     } else if mem.index.as_ref().map_or(false, |x| x.kind.family() != RegFamily::LEGACY) {
-        ecx.span_err(mem.span, "bad register type as index");
+        if report {
+            ecx.span_err(mem.span, "bad register type as index");
+        }
         return Err(None);
     }
 
     // RIP as base with index
     if mem.base == RegId::RIP && mem.index.is_some() {
-        ecx.span_err(mem.span, "'rip' cannot be used as base when an index is present");
+        if report {
+            ecx.span_err(mem.span, "'rip' cannot be used as base when an index is present");
+        }
         return Err(None);
     }
 
@@ -649,7 +1357,9 @@ fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<S
             swap(&mut mem.base, &mut mem.index);
         } else {
             // as we always fill the base field first this is impossible to satisfy
-            ecx.span_err(mem.span, "'rsp' cannot be used as index field");
+            if report {
+                ecx.span_err(mem.span, "'rsp' cannot be used as index field");
+            }
             return Err(None);
         }
     }
@@ -665,13 +1375,15 @@ fn sanitize_memoryref(ecx: &ExtCtxt, mem: &mut MemoryRef) -> Result<(), Option<S
     Ok(())
 }
 
-fn match_op_format(ecx: &ExtCtxt, ident: Ident, args: &mut [Arg]) -> Result<&'static Opdata, Option<String>> {
+fn match_op_format(ecx: &ExtCtxt, ident: Ident, args: &mut [Arg], report: bool) -> Result<&'static Opdata, Option<String>> {
     let name = &*ident.node.name.as_str();
 
     let data = if let Some(data) = get_mnemnonic_data(name) {
         data
     } else {
-        ecx.span_err(ident.span, &format!("'{}' is not a valid instruction", name));
+        if report {
+            ecx.span_err(ident.span, &format!("'{}' is not a valid instruction", name));
+        }
         return Err(None);
     };
 
@@ -851,7 +1563,7 @@ fn match_format_string(fmtstr: &'static [u8], mut args: &mut [Arg]) -> Result<()
     Ok(())
 }
 
-fn get_legacy_prefixes(ecx: &ExtCtxt, fmt: &'static Opdata, idents: Vec<Ident>) -> Result<(Option<u8>, Option<u8>), Option<String>> {
+fn get_legacy_prefixes(ecx: &ExtCtxt, fmt: &'static Opdata, idents: Vec<Ident>, report: bool) -> Result<(Option<u8>, Option<u8>), Option<String>> {
     let mut group1 = None;
     let mut group2 = None;
 
@@ -861,27 +1573,35 @@ fn get_legacy_prefixes(ecx: &ExtCtxt, fmt: &'static Opdata, idents: Vec<Ident>)
             "rep"   => if fmt.flags.contains(REP) {
                 (&mut group1, 0xF3)
             } else {
-                ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                if report {
+                    ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                }
                 return Err(None);
             },
             "repe"  |
             "repz"  => if fmt.flags.contains(REPE) {
                 (&mut group1, 0xF3)
             } else {
-                ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                if report {
+                    ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                }
                 return Err(None);
             },
             "repnz" |
             "repne" => if fmt.flags.contains(REP) {
                 (&mut group1, 0xF2)
             } else {
-                ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                if report {
+                    ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                }
                 return Err(None);
             },
             "lock"  => if fmt.flags.contains(LOCK) {
                 (&mut group1, 0xF0)
             } else {
-                ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                if report {
+                    ecx.span_err(prefix.span, &format!("Cannot use prefix {} on this instruction", name));
+                }
                 return Err(None);
             },
             "ss"    => (&mut group2, 0x36),
@@ -893,7 +1613,9 @@ fn get_legacy_prefixes(ecx: &ExtCtxt, fmt: &'static Opdata, idents: Vec<Ident>)
             _       => panic!("unimplemented prefix")
         };
         if group.is_some() {
-            ecx.span_err(prefix.span, "Duplicate prefix group");
+            if report {
+                ecx.span_err(prefix.span, "Duplicate prefix group");
+            }
             return Err(None);
         }
         *group = Some(value);
@@ -1,6 +1,7 @@
 use std::borrow::Cow;
 
-use compiler::{Opdata, FormatStringIterator};
+use x64data::{Opdata, FormatStringIterator};
+use compiler::Stmt;
 use x64data::flags::*;
 
 pub fn format_opdata_list(name: &str, data: &[Opdata]) -> String {
@@ -104,3 +105,145 @@ fn format_arg(ty: u8, mut size: u8, opsize: u8) -> Cow<'static, str> {
         _ => panic!("invalid formatting data")
     }
 }
+
+/// Formats the statements an instruction compiled to for the `.listing` directive. Statically
+/// known bytes are shown in hex, while fields that are only filled in at runtime are shown
+/// as placeholders.
+pub fn format_stmts(stmts: &[Stmt]) -> String {
+    let mut parts = Vec::new();
+    for stmt in stmts {
+        parts.push(match *stmt {
+            Stmt::Const(byte)         => format!("{:02X}", byte),
+            Stmt::ExprConst(_)        => "<reg>".into(),
+            Stmt::Var(_, size)        => format!("<imm{}>", size.in_bytes() as u32 * 8),
+            Stmt::DynScale(_, _)      => "<sib>".into(),
+            Stmt::Extend(_)           => "<bytes>".into(),
+            Stmt::Align(_)            => "<align>".into(),
+            Stmt::AlignPatchable(_, _) => "<patchable>".into(),
+            Stmt::GlobalJumpTarget(ref ident, size)   => format!("(rel{} ->{})", size.in_bytes() as u32 * 8, ident.node.name),
+            Stmt::ForwardJumpTarget(ref ident, size)  => format!("(rel{} >{})", size.in_bytes() as u32 * 8, ident.node.name),
+            Stmt::BackwardJumpTarget(ref ident, size) => format!("(rel{} <{})", size.in_bytes() as u32 * 8, ident.node.name),
+            Stmt::DynamicJumpTarget(_, size)          => format!("(rel{} =>dynamic)", size.in_bytes() as u32 * 8),
+            Stmt::Conditional(ref branches, _) => branches.iter().map(|&(_, ref stmts)| format!("{{{}}}", format_stmts(stmts))).collect::<Vec<_>>().join(" | "),
+            Stmt::JumpPatchPoint(_, _) |
+            Stmt::ImmediatePatchPoint(_, _) => "(patchpoint)".into(),
+            _ => continue
+        });
+    }
+    parts.join(" ")
+}
+
+/// Formats all forms of a mnemnonic as a JSON object, for consumption by external tooling.
+/// Operand kinds and sizes are decoded from the format strings, and the flags are split up
+/// into the encoding, mandatory prefixes, operand size rules and prefix validity.
+/// Operand sizes are given in bits, as `"auto"` when they follow the operand size selected
+/// by `auto_size`, as `"any"` when any size is accepted and as `null` when none is given.
+/// The `xmm` kinds refer to ymm registers when their size is 256.
+pub fn format_opdata_json(name: &str, data: &[Opdata]) -> String {
+    let forms: Vec<String> = data.iter().map(|d| format_form_json(name, d)).collect();
+    format!("{{\"mnemonic\": {}, \"forms\": [\n    {}\n]}}", json_str(name), forms.join(",\n    "))
+}
+
+fn format_form_json(name: &str, data: &Opdata) -> String {
+    let operands: Vec<String> = FormatStringIterator::new(data.args).map(|(ty, size)| format_arg_json(ty, size)).collect();
+    let opcode: Vec<String> = data.ops.iter().map(|b| format!("\"{:02X}\"", b)).collect();
+    let reg = if data.reg == 0xFF { "null".to_string() } else { data.reg.to_string() };
+
+    let encoding = if data.flags.contains(VEX_OP) { "vex" }
+              else if data.flags.contains(XOP_OP) { "xop" }
+              else                                { "legacy" };
+
+    // PREF_66 is the same bit as WORD_SIZE. It is only a mandatory prefix for SSE, VEX and XOP
+    // forms, in all other forms it's the operand size prefix of 16-bit operations.
+    let sse = data.flags.intersects(VEX_OP | XOP_OP) || FormatStringIterator::new(data.args).any(|(ty, _)| match ty {
+        b'y' | b'w' | b'k' | b'l' => true,
+        _ => false
+    });
+
+    let mut prefixes = Vec::new();
+    for &(flag, prefix) in &[(PREF_66, "\"66\""), (PREF_67, "\"67\""), (PREF_F0, "\"F0\""), (PREF_F2, "\"F2\""), (PREF_F3, "\"F3\"")] {
+        if data.flags.contains(flag) && (flag != PREF_66 || sse) {
+            prefixes.push(prefix);
+        }
+    }
+
+    // the operand sizes selected by '*' and how each of them is encoded
+    let auto_size = if data.flags.contains(AUTO_SIZE) { "{\"16\": \"66\", \"32\": null, \"64\": \"W\"}" }
+               else if data.flags.contains(AUTO_NO32) { "{\"16\": \"66\", \"64\": null}" }
+               else if data.flags.contains(AUTO_REXW) { "{\"32\": null, \"64\": \"W\"}" }
+               else if data.flags.contains(AUTO_VEXL) { "{\"128\": null, \"256\": \"L\"}" }
+               else                                   { "null" };
+
+    let order = if data.flags.contains(ENC_MR) { "mr" }
+           else if data.flags.contains(ENC_VM) { "vm" }
+           else                                { "rm" };
+
+    let syntax: Vec<String> = format_opdata(name, data).iter().map(|s| json_str(&s.replace(">>> ", ""))).collect();
+
+    format!("{{\"operands\": [{}], \"opcode\": [{}], \"reg\": {}, \"encoding\": \"{}\", \"prefixes\": [{}], \
+             \"auto_size\": {}, \"rex_w\": {}, \"vex_l\": {}, \"short_arg\": {}, \"operand_order\": \"{}\", \
+             \"lock\": {}, \"rep\": {}, \"repe\": {}, \"syntax\": [{}]}}",
+            operands.join(", "), opcode.join(", "), reg, encoding, prefixes.join(", "),
+            auto_size, data.flags.contains(WITH_REXW), data.flags.contains(WITH_VEXL), data.flags.contains(SHORT_ARG), order,
+            data.flags.contains(LOCK), data.flags.contains(REP), data.flags.contains(REPE), syntax.join(", "))
+}
+
+fn format_arg_json(ty: u8, size: u8) -> String {
+    let size = match size {
+        b'b' => "8",
+        b'w' => "16",
+        b'd' => "32",
+        b'q' => "64",
+        b'p' => "80",
+        b'o' => "128",
+        b'h' => "256",
+        b'*' => "\"auto\"",
+        b'?' => "\"any\"",
+        b'!' => "null",
+        _ => panic!("invalid formatting data")
+    };
+
+    let (kind, register) = match ty {
+        b'i' => ("imm", None),
+        b'o' => ("rel", None),
+        b'm' => ("mem", None),
+        b'k' => ("vsib32", None),
+        b'l' => ("vsib64", None),
+        b'r' => ("reg", None),
+        b'f' => ("st", None),
+        b'x' => ("mmx", None),
+        b'y' => ("xmm", None),
+        b's' => ("segreg", None),
+        b'c' => ("creg", None),
+        b'd' => ("dreg", None),
+        b'v' => ("reg/mem", None),
+        b'u' => ("mmx/mem", None),
+        b'w' => ("xmm/mem", None),
+        b'A'...b'P' => {
+            let i = ty as usize - 'A' as usize;
+            ("fixed_reg", Some(if i < 4 { format!("r{}x", REGS[i]) } else { format!("r{}", REGS[i]) }))
+        },
+        b'Q'...b'V' => ("fixed_segreg", Some(SEGREGS[ty as usize - 'Q' as usize].to_string())),
+        b'W' => ("fixed_creg", Some("cr8".to_string())),
+        b'X' => ("fixed_st", Some("st0".to_string())),
+        _ => panic!("invalid formatting data")
+    };
+
+    match register {
+        Some(register) => format!("{{\"kind\": \"{}\", \"size\": {}, \"register\": {}}}", kind, size, json_str(&register)),
+        None => format!("{{\"kind\": \"{}\", \"size\": {}}}", kind, size)
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut buf = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            c => buf.push(c)
+        }
+    }
+    buf.push('"');
+    buf
+}
@@ -5,9 +5,8 @@ extern crate syntax;
 extern crate rustc_plugin;
 #[macro_use]
 extern crate lazy_static;
-#[macro_use]
-extern crate bitflags;
 extern crate owning_ref;
+pub extern crate dynasm_x64data as x64data;
 
 use rustc_plugin::registry::Registry;
 use syntax::ext::base::{SyntaxExtension, ExtCtxt, MacResult, DummyResult};
@@ -27,7 +26,6 @@ use owning_ref::{OwningRef, RwLockReadGuardRef};
 
 pub mod parser;
 pub mod compiler;
-pub mod x64data;
 pub mod serialize;
 pub mod debug;
 
@@ -55,12 +53,27 @@ fn main<'cx>(ecx: &'cx mut ExtCtxt, span: Span, token_tree: &[TokenTree])
     // within dynasm
     let token_tree = ecx.expander().fold_tts(token_tree);
 
-    let mut parser = ecx.new_parser_from_tts(&token_tree);
+    let stmts = if let Some(stmts) = expand_tts(ecx, &token_tree) {
+        stmts
+    } else {
+        return DummyResult::any(span)
+    };
+
+    Box::new(DynAsm {
+        ecx: ecx,
+        stmts: stmts
+    })
+}
+
+/// Parses, compiles and serializes the contents of a `dynasm!` invocation into the statements
+/// it expands to. Any errors are reported through `ecx`, in which case `None` is returned.
+pub fn expand_tts(ecx: &mut ExtCtxt, token_tree: &[TokenTree]) -> Option<Vec<ast::Stmt>> {
+    let mut parser = ecx.new_parser_from_tts(token_tree);
 
     // construct an ast of assembly nodes
     let (name, ast) = match parser::parse(ecx, &mut parser) {
         Ok(ast) => ast,
-        Err(mut e) => {e.emit(); return DummyResult::any(span)}
+        Err(mut e) => {e.emit(); return None}
     };
 
     // println!("{:?}", ast);
@@ -68,15 +81,10 @@ fn main<'cx>(ecx: &'cx mut ExtCtxt, span: Span, token_tree: &[TokenTree])
     let stmts = if let Ok(stmts) = compiler::compile(ecx, ast) {
         stmts
     } else {
-        return DummyResult::any(span)
+        return None
     };
 
-    let stmts = serialize::serialize(ecx, name, stmts);
-
-    Box::new(DynAsm {
-        ecx: ecx,
-        stmts: stmts
-    })
+    Some(serialize::serialize(ecx, name, stmts))
 }
 
 struct DynAsm<'cx, 'a: 'cx> {
@@ -104,7 +112,13 @@ impl<'cx, 'a> MacResult for DynAsm<'cx, 'a> {
 // Crate local data implementation.
 
 pub struct DynasmData {
-    aliases: HashMap<ast::Name, (parser::RegId, parser::Size)>
+    // aliases defined by .alias and .mod_alias, keyed by the path of the module they were defined
+    // in (empty for .alias) and their name
+    aliases: HashMap<(Vec<ast::Name>, ast::Name), (parser::RegId, parser::Size)>,
+    // constants defined by .equ
+    constants: HashMap<ast::Name, i64>,
+    // structure layouts defined by .struct
+    structs: HashMap<ast::Name, parser::Structure>
 }
 
 pub struct CrateLocalData {
@@ -140,7 +154,9 @@ pub fn crate_local_data(ecx: &ExtCtxt) -> CrateLocalData {
     {
         let mut lock = DYNASM_STORAGE.write().unwrap();
         lock.insert(id, RwLock::new(DynasmData {
-            aliases: HashMap::new()
+            aliases: HashMap::new(),
+            constants: HashMap::new(),
+            structs: HashMap::new()
         }));
     }
     CrateLocalData {
@@ -4,13 +4,19 @@ use syntax::parse::parser::{Parser, PathStyle};
 use syntax::parse::token;
 use syntax::parse::PResult;
 use syntax::ast;
+use syntax::attr;
 use syntax::ptr::P;
 use syntax::codemap::{Spanned, Span};
+use syntax::tokenstream::{TokenTree, Delimited};
+use syntax::fold::{self, Folder};
 
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::cmp::PartialEq;
+use std::mem;
+use std::rc::Rc;
 
-use serialize::{offset_of, size_of, add_exprs, size_of_scale_expr};
+use serialize::{offset_of, size_of, size_of_field, add_exprs, size_of_scale_expr};
 
 pub type Ident = Spanned<ast::Ident>;
 
@@ -24,9 +30,12 @@ pub enum Item {
     Label(LabelType),
     Directive(Ident, Vec<Arg>, Span),
     Stmt(ast::Stmt),
+    // runtime conditional assembly: the branches with their conditions, the else branch and
+    // the span of the .if directive
+    Conditional(Vec<(P<ast::Expr>, Vec<Item>)>, Vec<Item>, Span),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Arg {
     Indirect(MemoryRef), // indirect memory reference supporting scale, index, base and displacement.
     Direct(Spanned<Register>), // a bare register (rax, ...)
@@ -36,7 +45,88 @@ pub enum Arg {
     Invalid // placeholder value
 }
 
-#[derive(Debug)]
+/// The scopes an alias can be defined in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AliasScope {
+    Local,  // until the end of the dynasm! block
+    Module, // the rest of the current module and its submodules
+    Crate   // the rest of the crate
+}
+
+/// What an alias stands for. Dynamic registers and memory references contain expressions,
+/// which are copied into every instruction using the alias and are therefore evaluated again
+/// at every use instead of once at the definition.
+#[derive(Debug, Clone)]
+pub enum AliasValue {
+    Register(Register),
+    Memory(MemoryRef)
+}
+
+/// A structure layout defined by `.struct`. Fields are laid out like those of a C structure.
+#[derive(Debug, Clone)]
+pub struct Structure {
+    pub fields: HashMap<ast::Name, Field>,
+    pub size: u64,
+    pub align: u64
+}
+
+/// A field of a structure defined by `.struct`.
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub offset: u64,
+    // the size of the field, or of its elements if it is an array. None for structures.
+    pub size: Option<Size>,
+    // the structure the field contains, if any
+    pub structure: Option<ast::Name>
+}
+
+/// The aliases visible while parsing a `dynasm!` block. Aliases local to the block are kept
+/// here, while module and crate aliases are stored in the crate local data keyed by the module
+/// path they were defined in, where the crate is the empty path. As these outlive the block,
+/// they can only refer to static registers.
+pub struct Aliases {
+    local: HashMap<ast::Name, AliasValue>,
+    module: Vec<ast::Name>
+}
+
+// state kept while parsing a single dynasm! block
+struct ParseState {
+    aliases: Aliases,
+    macros: HashMap<ast::Name, Macro>,
+    // the nesting depth of macro expansions
+    depth: usize,
+    // the amount of macros expanded so far, used to generate unique label names
+    expansions: usize,
+    // the blocks that are currently open, which decide what .else and .endif belong to
+    blocks: Vec<Block>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Conditional, // .if, handled by the parser
+    IfCc,        // .if_cc, handled by the compiler
+    WhileCc      // .while_cc, handled by the compiler
+}
+
+// a macro defined using .macro
+struct Macro {
+    params: Vec<ast::Name>,
+    body: Vec<TokenTree>,
+    // local labels defined in the body, which are renamed at every expansion
+    labels: Vec<ast::Name>
+}
+
+const MAX_MACRO_DEPTH: usize = 64;
+
+// the directives ending a branch of a conditional
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BranchEnd {
+    Elif,
+    Else,
+    Endif
+}
+
+#[derive(Debug, Clone)]
 pub struct MemoryRef {
     pub index:      Option<Register>,
     pub scale:      isize,
@@ -44,9 +134,18 @@ pub struct MemoryRef {
     pub base:       Option<Register>,
     pub disp:       Option<P<ast::Expr>>,
     pub size:       Option<Size>,
+    // the size of the field referred to by a type map, used when no size is given
+    pub size_expr:  Option<P<ast::Expr>>,
     pub span:       Span
 }
 
+/// A field in the path of a type map: `Type.name` or `Type.0`.
+#[derive(Debug, Clone, Copy)]
+pub enum TypeMapField {
+    Named(Ident),
+    Positional(Spanned<usize>)
+}
+
 #[derive(Debug)]
 pub enum LabelType {
     Global(Ident),         // . label :
@@ -54,7 +153,7 @@ pub enum LabelType {
     Dynamic(P<ast::Expr>), // => expr :
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum JumpType {
     // note: these symbol choices try to avoid stuff that is a valid starting symbol for parse_expr
     // in order to allow the full range of expressions to be used. the only currently existing ambiguity is
@@ -294,6 +393,85 @@ impl Size {
     }
 }
 
+impl Aliases {
+    pub fn new(ecx: &ExtCtxt) -> Aliases {
+        Aliases {
+            local: HashMap::new(),
+            module: ecx.current_expansion.module.mod_path.iter().map(|i| i.name).collect()
+        }
+    }
+
+    /// Finds the innermost definition of `name`.
+    pub fn lookup(&self, ecx: &ExtCtxt, name: ast::Name) -> Option<AliasValue> {
+        if let Some(value) = self.local.get(&name) {
+            return Some(value.clone());
+        }
+
+        let global_data = super::crate_local_data(ecx);
+        let lock = global_data.read();
+        for i in (0 .. self.module.len() + 1).rev() {
+            if let Some(&(id, size)) = lock.aliases.get(&(self.module[..i].to_vec(), name)) {
+                return Some(AliasValue::Register(Register::new_static(size, id)));
+            }
+        }
+        None
+    }
+
+    /// Defines `name` in `scope`, replacing any earlier definition in the same scope. Crate-wide
+    /// aliases cannot be redefined. Only static registers can be defined outside of the local scope.
+    pub fn define(&mut self, ecx: &ExtCtxt, scope: AliasScope, name: ast::Name, value: AliasValue) -> Result<(), String> {
+        let path = match scope {
+            AliasScope::Local => {
+                self.local.insert(name, value);
+                return Ok(());
+            },
+            AliasScope::Module => self.module.clone(),
+            AliasScope::Crate => Vec::new()
+        };
+
+        let reg = match value {
+            AliasValue::Register(Register {kind: RegKind::Static(id), size}) => (id, size),
+            _ => panic!("only static registers can be aliased outside of a dynasm! block")
+        };
+
+        let global_data = super::crate_local_data(ecx);
+        let mut lock = global_data.write();
+        match lock.aliases.entry((path, name)) {
+            Entry::Occupied(_) if scope == AliasScope::Crate => Err(format!("Duplicate alias definition, alias '{}' was earlier defined", name.as_str())),
+            Entry::Occupied(mut o) => {
+                o.insert(reg);
+                Ok(())
+            },
+            Entry::Vacant(v) => {
+                v.insert(reg);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes the local definition of `name`, or else its definition in the current module.
+    /// Definitions in enclosing modules and crate-wide aliases cannot be removed, as they are
+    /// also visible outside of the current module.
+    pub fn undefine(&mut self, ecx: &ExtCtxt, name: ast::Name) -> Result<(), String> {
+        if self.local.remove(&name).is_some() {
+            return Ok(());
+        }
+
+        let global_data = super::crate_local_data(ecx);
+        let mut lock = global_data.write();
+        if !self.module.is_empty() && lock.aliases.remove(&(self.module.clone(), name)).is_some() {
+            return Ok(());
+        }
+        if lock.aliases.contains_key(&(Vec::new(), name)) {
+            Err(format!("Alias '{}' is defined crate-wide and cannot be removed", name.as_str()))
+        } else if (1 .. self.module.len()).any(|i| lock.aliases.contains_key(&(self.module[..i].to_vec(), name))) {
+            Err(format!("Alias '{}' is defined in an enclosing module and cannot be removed here", name.as_str()))
+        } else {
+            Err(format!("Unknown alias '{}'", name.as_str()))
+        }
+    }
+}
+
 /*
  * Code
  */
@@ -306,13 +484,50 @@ pub fn parse<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, (P<ast::
     let name = try!(parser.parse_expr());
 
     let mut ins = Vec::new();
+    let mut state = ParseState {
+        aliases: Aliases::new(ecx),
+        macros: HashMap::new(),
+        depth: 0,
+        expansions: 0,
+        blocks: Vec::new()
+    };
+    try!(parse_items(ecx, parser, &mut state, &mut ins, false));
 
+    Ok((name, ins))
+}
+
+// parses items until the end of the input or, if in_branch is set, the end of the current
+// branch of a conditional.
+fn parse_items<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>, state: &mut ParseState, ins: &mut Vec<Item>, in_branch: bool) -> PResult<'a, Option<BranchEnd>> {
     while !parser.check(&token::Eof) {
 
         try!(parser.expect(&token::Semi));
 
         let startspan = parser.span;
 
+        if let Some(end) = eat_branch_end(parser) {
+            let span = Span {hi: parser.prev_span.hi, ..startspan};
+
+            // the .else and .endif of an .if_cc are passed on to the compiler
+            if state.blocks.last() == Some(&Block::IfCc) {
+                let name = match end {
+                    BranchEnd::Else => "else",
+                    BranchEnd::Endif => {
+                        state.blocks.pop();
+                        "endif"
+                    },
+                    BranchEnd::Elif => return Err(parser.span_fatal(span, "'.elif' cannot be used with '.if_cc'"))
+                };
+                ins.push(Item::Directive(Spanned {node: token::str_to_ident(name), span: span}, Vec::new(), span));
+                continue;
+            }
+
+            if !in_branch {
+                return Err(parser.span_fatal(span, "Conditional directive without a matching '.if'"));
+            }
+            return Ok(Some(end));
+        }
+
         if parser.eat(&token::Semi) {
             let stmt = try!(parser.parse_stmt());
             if let Some(stmt) = stmt {
@@ -342,6 +557,26 @@ pub fn parse<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, (P<ast::
         }
 
         let is_directive = parser.eat(&token::Dot);
+
+        // macro definitions are handled while parsing. As `macro` is a reserved keyword it
+        // cannot be parsed as an identifier.
+        if is_directive && eat_pseudo_keyword(parser, "macro") {
+            try!(parse_macro(parser, state));
+            continue;
+        }
+
+        // structure definitions. `struct` is a keyword too.
+        if is_directive && eat_pseudo_keyword(parser, "struct") {
+            try!(parse_struct(ecx, parser, &state.aliases));
+            continue;
+        }
+
+        // conditionals need to be parsed as a whole. `if` is a keyword as well.
+        if is_directive && eat_pseudo_keyword(parser, "if") {
+            try!(parse_conditional(ecx, parser, state, ins, startspan));
+            continue;
+        }
+
         // parse the first part of an op or a label
 
         let mut span = parser.span;
@@ -354,6 +589,42 @@ pub fn parse<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, (P<ast::
             continue;
         }
 
+        // aliases take effect immediately, so they're handled while parsing
+        if is_directive {
+            let scope = match &*op.node.name.as_str() {
+                "alias"       => Some(Some(AliasScope::Crate)),
+                "mod_alias"   => Some(Some(AliasScope::Module)),
+                "local_alias" => Some(Some(AliasScope::Local)),
+                "unalias"     => Some(None),
+                _ => None
+            };
+            if let Some(scope) = scope {
+                try!(parse_alias(ecx, parser, &mut state.aliases, scope));
+                continue;
+            }
+
+            // as are constants
+            if &*op.node.name.as_str() == "equ" {
+                try!(parse_equ(ecx, parser, &state.aliases));
+                continue;
+            }
+
+            // structured control flow is compiled later, but the parser needs to know which
+            // block is open
+            match &*op.node.name.as_str() {
+                "if_cc" => state.blocks.push(Block::IfCc),
+                "while_cc" => state.blocks.push(Block::WhileCc),
+                "endwhile" => if state.blocks.last() == Some(&Block::WhileCc) {
+                    state.blocks.pop();
+                },
+                _ => ()
+            }
+        } else if state.macros.contains_key(&op.node.name) {
+            let args = try!(parse_macro_args(parser));
+            expand_macro(ecx, state, op, args, ins);
+            continue;
+        }
+
         // if we're parsing an instruction, read prefixes
 
         let mut ops = Vec::new();
@@ -369,10 +640,10 @@ pub fn parse<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, (P<ast::
         let mut args = Vec::new();
 
         if !parser.check(&token::Semi) && !parser.check(&token::Eof) {
-            args.push(try!(parse_arg(ecx, parser)));
+            args.push(try!(parse_arg(ecx, &state.aliases, parser)));
 
             while parser.eat(&token::Comma) {
-                args.push(try!(parse_arg(ecx, parser)));
+                args.push(try!(parse_arg(ecx, &state.aliases, parser)));
             }
         }
 
@@ -386,7 +657,429 @@ pub fn parse<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, (P<ast::
         }
     }
 
-    Ok((name, ins))
+    if in_branch {
+        return Err(parser.span_fatal(parser.span, "Unterminated conditional, expected '.endif'"));
+    }
+    Ok(None)
+}
+
+// parses the rest of a conditional, starting with the condition of the .if directive. Branches
+// with conditions that are known at compile time are pruned here, without parsing them.
+fn parse_conditional<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>, state: &mut ParseState, ins: &mut Vec<Item>, span: Span) -> PResult<'a, ()> {
+    // branches with runtime conditions
+    let mut branches = Vec::new();
+    // the branch taken when none of those are, if any
+    let mut otherwise = None;
+
+    state.blocks.push(Block::Conditional);
+
+    let mut condition = Some(fold_symbols(ecx, &state.aliases, try!(parser.parse_expr())).0);
+    loop {
+        let taken = match condition {
+            _ if otherwise.is_some() => Some(false),
+            Some(ref condition) => static_condition(ecx, condition),
+            None => Some(true)
+        };
+
+        let end = match taken {
+            Some(false) => try!(skip_branch(parser)),
+            Some(true) => {
+                let mut items = Vec::new();
+                let end = try!(parse_items(ecx, parser, state, &mut items, true));
+                otherwise = Some(items);
+                end.unwrap()
+            },
+            None => {
+                let mut items = Vec::new();
+                let end = try!(parse_items(ecx, parser, state, &mut items, true));
+                branches.push((condition.take().unwrap(), items));
+                end.unwrap()
+            }
+        };
+
+        condition = match end {
+            BranchEnd::Elif => Some(fold_symbols(ecx, &state.aliases, try!(parser.parse_expr())).0),
+            BranchEnd::Else => None,
+            BranchEnd::Endif => break
+        };
+    }
+
+    state.blocks.pop();
+
+    let otherwise = otherwise.unwrap_or_else(Vec::new);
+    if branches.is_empty() {
+        ins.extend(otherwise);
+    } else {
+        ins.push(Item::Conditional(branches, otherwise, span));
+    }
+    Ok(())
+}
+
+// evaluates conditions that are known at compile time. Macros inside of the dynasm! block
+// are not expanded yet, so cfg! is evaluated here against the configuration of the crate.
+fn static_condition(ecx: &ExtCtxt, expr: &ast::Expr) -> Option<bool> {
+    match expr.node {
+        ast::ExprKind::Lit(ref lit) => match lit.node {
+            ast::LitKind::Bool(value) => Some(value),
+            _ => None
+        },
+        ast::ExprKind::Paren(ref inner) => static_condition(ecx, inner),
+        ast::ExprKind::Unary(ast::UnOp::Not, ref inner) => static_condition(ecx, inner).map(|value| !value),
+        ast::ExprKind::Mac(ref mac) => {
+            let path = &mac.node.path;
+            if path.global || path.segments.len() != 1 || &*path.segments[0].identifier.name.as_str() != "cfg" {
+                return None;
+            }
+
+            // anything that isn't a single meta item is left for rustc to complain about
+            let mut parser = ecx.new_parser_from_tts(&mac.node.tts);
+            let meta = match parser.parse_meta_item() {
+                Ok(meta) => meta,
+                Err(mut e) => {
+                    e.cancel();
+                    return None;
+                }
+            };
+            if !parser.check(&token::Eof) {
+                return None;
+            }
+            Some(attr::cfg_matches(&ecx.cfg, &meta, ecx.parse_sess, None))
+        },
+        _ => None
+    }
+}
+
+// skips the tokens of a branch that isn't taken, including any nested conditionals.
+fn skip_branch<'a>(parser: &mut Parser<'a>) -> PResult<'a, BranchEnd> {
+    let mut depth = 0;
+    loop {
+        if parser.check(&token::Eof) {
+            return Err(parser.span_fatal(parser.span, "Unterminated conditional, expected '.endif'"));
+        }
+        if !parser.eat(&token::Semi) {
+            try!(parser.parse_token_tree());
+            continue;
+        }
+
+        // .if_cc is ended by .endif as well
+        if parser.look_ahead(0, |t| *t == token::Dot) && parser.look_ahead(1, |t| is_ident_named(t, "if") || is_ident_named(t, "if_cc")) {
+            depth += 1;
+        } else if let Some(end) = eat_branch_end(parser) {
+            if depth == 0 {
+                return Ok(end);
+            } else if end == BranchEnd::Endif {
+                depth -= 1;
+            }
+        }
+    }
+}
+
+// eats a directive ending a branch of a conditional
+fn eat_branch_end(parser: &mut Parser) -> Option<BranchEnd> {
+    if !parser.check(&token::Dot) {
+        return None;
+    }
+
+    let end = parser.look_ahead(1, |t| if is_ident_named(t, "elif") {
+        Some(BranchEnd::Elif)
+    } else if is_ident_named(t, "else") {
+        Some(BranchEnd::Else)
+    } else if is_ident_named(t, "endif") {
+        Some(BranchEnd::Endif)
+    } else {
+        None
+    });
+
+    if end.is_some() {
+        parser.bump();
+        parser.bump();
+    }
+    end
+}
+
+fn is_ident_named(token: &token::Token, name: &str) -> bool {
+    match *token {
+        token::Ident(ident) => &*ident.name.as_str() == name,
+        _ => false
+    }
+}
+
+// parses the arguments of an alias directive, and defines or undefines (if scope is None)
+// the alias.
+fn parse_alias<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>, aliases: &mut Aliases, scope: Option<AliasScope>) -> PResult<'a, ()> {
+    // the name is parsed as an identifier, so existing aliases can be shadowed
+    let start = parser.span;
+    let name = try!(parser.parse_ident());
+
+    let scope = if let Some(scope) = scope {
+        scope
+    } else {
+        if let Err(e) = aliases.undefine(ecx, name.name) {
+            ecx.span_err(start, &e);
+        }
+        return Ok(());
+    };
+
+    try!(parser.expect(&token::Comma));
+    let value = match try!(parse_arg(ecx, aliases, parser)) {
+        Arg::Direct(Spanned {node: reg, ..}) => AliasValue::Register(reg),
+        Arg::Indirect(mem) => AliasValue::Memory(mem),
+        Arg::Invalid => return Ok(()),
+        _ => {
+            ecx.span_err(Span {hi: parser.prev_span.hi, ..start}, "The second argument to alias should be a register or a memory reference");
+            return Ok(());
+        }
+    };
+
+    // the expressions in dynamic registers and memory references cannot outlive the block
+    let is_static = match value {
+        AliasValue::Register(Register {kind: RegKind::Static(_), ..}) => true,
+        _ => false
+    };
+    if scope != AliasScope::Local && !is_static {
+        ecx.span_err(Span {hi: parser.prev_span.hi, ..start}, "Only static registers can be aliased outside of a single dynasm! block, use .local_alias instead");
+        return Ok(());
+    }
+
+    if let Err(e) = aliases.define(ecx, scope, name.name, value) {
+        ecx.span_err(Span {hi: parser.prev_span.hi, ..start}, &e);
+    }
+    Ok(())
+}
+
+// parses the rest of an .equ directive. Constants are visible in the rest of the crate.
+fn parse_equ<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>, aliases: &Aliases) -> PResult<'a, ()> {
+    let start = parser.span;
+    let name = try!(parser.parse_ident());
+    let span = Span {hi: parser.prev_span.hi, ..start};
+    try!(parser.expect(&token::Comma));
+
+    let (expr, _) = fold_symbols(ecx, aliases, try!(parser.parse_expr()));
+
+    // registers and aliases are never replaced by constants, so such a constant could never be used
+    if is_register_or_alias(ecx, aliases, &ecx.expr_ident(span, name)) {
+        ecx.span_err(span, &format!("'{}' is a register or an alias and cannot be used as the name of a constant", name.name.as_str()));
+        return Ok(());
+    }
+
+    match eval_const(&expr) {
+        Some(value) => {
+            let global_data = super::crate_local_data(ecx);
+            let mut lock = global_data.write();
+            lock.constants.insert(name.name, value);
+        },
+        None => ecx.span_err(expr.span, "The value of a constant should be an integer expression that can be evaluated at compile time")
+    }
+    Ok(())
+}
+
+// parses the rest of a .struct directive: a name followed by fields between braces.
+// Structures are visible in the rest of the crate.
+fn parse_struct<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>, aliases: &Aliases) -> PResult<'a, ()> {
+    let name = try!(parser.parse_ident());
+    try!(parser.expect(&token::OpenDelim(token::Brace)));
+
+    let mut structure = Structure {
+        fields: HashMap::new(),
+        size: 0,
+        align: 1
+    };
+
+    while !parser.eat(&token::CloseDelim(token::Brace)) {
+        let start = parser.span;
+        let field = try!(parser.parse_ident());
+        try!(parser.expect(&token::Colon));
+        let (size, align, scalar, inner) = try!(parse_field_type(ecx, parser, aliases));
+
+        let offset = (structure.size + align - 1) / align * align;
+        if structure.fields.insert(field.name, Field {offset: offset, size: scalar, structure: inner}).is_some() {
+            return Err(parser.span_fatal(start, &format!("Duplicate field '{}'", field.name.as_str())));
+        }
+        structure.size = offset + size;
+        if align > structure.align {
+            structure.align = align;
+        }
+
+        if !parser.eat(&token::Comma) {
+            try!(parser.expect(&token::CloseDelim(token::Brace)));
+            break;
+        }
+    }
+    structure.size = (structure.size + structure.align - 1) / structure.align * structure.align;
+
+    let global_data = super::crate_local_data(ecx);
+    let mut lock = global_data.write();
+    lock.structs.insert(name.name, structure);
+    Ok(())
+}
+
+// parses the type of a structure field, which is a size, the name of a structure or an array
+// of either. Returns its size, alignment, element size and structure.
+fn parse_field_type<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>, aliases: &Aliases) -> PResult<'a, (u64, u64, Option<Size>, Option<ast::Name>)> {
+    let start = parser.span;
+
+    if parser.eat(&token::OpenDelim(token::Bracket)) {
+        let (size, align, scalar, _) = try!(parse_field_type(ecx, parser, aliases));
+        try!(parser.expect(&token::Semi));
+        let (count, _) = fold_symbols(ecx, aliases, try!(parser.parse_expr()));
+        let count = match eval_const(&count) {
+            Some(count) if count >= 0 => count as u64,
+            _ => return Err(parser.span_fatal(count.span, "The length of an array should be a positive integer that can be evaluated at compile time"))
+        };
+        try!(parser.expect(&token::CloseDelim(token::Bracket)));
+        return Ok((size * count, align, scalar, None));
+    }
+
+    if let Some(size) = eat_size_hint(parser) {
+        let bytes = size.in_bytes() as u64;
+        return Ok((bytes, bytes, Some(size), None));
+    }
+
+    let name = try!(parser.parse_ident()).name;
+    let global_data = super::crate_local_data(ecx);
+    let lock = global_data.read();
+    let layout = lock.structs.get(&name).map(|structure| (structure.size, structure.align));
+    match layout {
+        Some((size, align)) => Ok((size, align, None, Some(name))),
+        None => Err(parser.span_fatal(Span {hi: parser.prev_span.hi, ..start}, &format!("Unknown structure '{}'", name.as_str())))
+    }
+}
+
+// parses the rest of a macro definition, up to and including the matching .endmacro.
+fn parse_macro<'a>(parser: &mut Parser<'a>, state: &mut ParseState) -> PResult<'a, ()> {
+    let name = try!(parser.parse_ident());
+
+    let mut params = Vec::new();
+    if !parser.check(&token::Semi) && !parser.check(&token::Eof) {
+        params.push(try!(parser.parse_ident()).name);
+        while parser.eat(&token::Comma) {
+            params.push(try!(parser.parse_ident()).name);
+        }
+    }
+
+    // the body is everything up to the next "; .endmacro"
+    let mut body = Vec::new();
+    loop {
+        if parser.check(&token::Eof) {
+            return Err(parser.span_fatal(parser.span, "Unterminated macro definition, expected '.endmacro'"));
+        }
+        if parser.check(&token::Semi) &&
+           parser.look_ahead(1, |t| *t == token::Dot) &&
+           parser.look_ahead(2, |t| is_ident_named(t, "endmacro")) {
+            break;
+        }
+        if parser.check(&token::Semi) &&
+           parser.look_ahead(1, |t| *t == token::Dot) &&
+           parser.look_ahead(2, |t| is_ident_named(t, "macro")) {
+            return Err(parser.span_fatal(parser.span, "Macro definitions cannot be nested"));
+        }
+        body.push(try!(parser.parse_token_tree()));
+    }
+    parser.bump();
+    parser.bump();
+    parser.bump();
+
+    // find local label definitions, which have the form "; name :"
+    let mut labels = Vec::new();
+    for window in body.windows(3) {
+        if let (&TokenTree::Token(_, token::Semi), &TokenTree::Token(_, token::Ident(label)), &TokenTree::Token(_, token::Colon)) = (&window[0], &window[1], &window[2]) {
+            labels.push(label.name);
+        }
+    }
+
+    state.macros.insert(name.name, Macro {
+        params: params,
+        body: body,
+        labels: labels
+    });
+    Ok(())
+}
+
+// parses the arguments of a macro invocation as comma separated sequences of tokens.
+fn parse_macro_args<'a>(parser: &mut Parser<'a>) -> PResult<'a, Vec<Vec<TokenTree>>> {
+    let mut args = Vec::new();
+    if parser.check(&token::Semi) || parser.check(&token::Eof) {
+        return Ok(args);
+    }
+
+    let mut arg = Vec::new();
+    while !parser.check(&token::Semi) && !parser.check(&token::Eof) {
+        if parser.eat(&token::Comma) {
+            args.push(mem::replace(&mut arg, Vec::new()));
+        } else {
+            arg.push(try!(parser.parse_token_tree()));
+        }
+    }
+    args.push(arg);
+    Ok(args)
+}
+
+// substitutes the arguments into the body of the macro and parses the result. Errors are
+// reported directly, as they belong to the expansion instead of the invocation.
+fn expand_macro(ecx: &ExtCtxt, state: &mut ParseState, name: Ident, args: Vec<Vec<TokenTree>>, ins: &mut Vec<Item>) {
+    let tts = {
+        let mac = &state.macros[&name.node.name];
+        if args.len() != mac.params.len() {
+            ecx.span_err(name.span, &format!("Macro '{}' takes {} arguments but {} were given", name.node.name.as_str(), mac.params.len(), args.len()));
+            return;
+        }
+        if state.depth == MAX_MACRO_DEPTH {
+            ecx.span_err(name.span, &format!("Recursion limit reached while expanding macro '{}'", name.node.name.as_str()));
+            return;
+        }
+
+        // every expansion gets its own names for the local labels in the body
+        state.expansions += 1;
+        let id = state.expansions;
+        let labels: HashMap<_, _> = mac.labels.iter().map(|&label| {
+            (label, token::str_to_ident(&format!("__{}_{}", label.as_str(), id)))
+        }).collect();
+        let params: HashMap<_, _> = mac.params.iter().cloned().zip(args).collect();
+
+        substitute(&mac.body, &params, &labels)
+    };
+
+    let mut parser = ecx.new_parser_from_tts(&tts);
+    state.depth += 1;
+    if let Err(mut e) = parse_items(ecx, &mut parser, state, ins, false) {
+        e.emit();
+    }
+    state.depth -= 1;
+}
+
+// replaces parameters by their arguments and renames local labels. Only label definitions
+// ("; name :") and jump targets (">name" and "<name") are renamed.
+fn substitute(tts: &[TokenTree], params: &HashMap<ast::Name, Vec<TokenTree>>, labels: &HashMap<ast::Name, ast::Ident>) -> Vec<TokenTree> {
+    let mut result = Vec::new();
+    for (i, tt) in tts.iter().enumerate() {
+        match *tt {
+            TokenTree::Token(span, token::Ident(ident)) => {
+                let is_label = match (if i > 0 { tts.get(i - 1) } else { None }, tts.get(i + 1)) {
+                    (Some(&TokenTree::Token(_, token::Gt)), _) |
+                    (Some(&TokenTree::Token(_, token::Lt)), _) |
+                    (Some(&TokenTree::Token(_, token::Semi)), Some(&TokenTree::Token(_, token::Colon))) => true,
+                    _ => false
+                };
+                let label = if is_label { labels.get(&ident.name) } else { None };
+
+                if let Some(&label) = label {
+                    result.push(TokenTree::Token(span, token::Ident(label)));
+                } else if let Some(arg) = params.get(&ident.name) {
+                    result.extend(arg.iter().cloned());
+                } else {
+                    result.push(tt.clone());
+                }
+            },
+            TokenTree::Delimited(span, ref delimited) => result.push(TokenTree::Delimited(span, Rc::new(Delimited {
+                delim: delimited.delim,
+                open_span: delimited.open_span,
+                tts: substitute(&delimited.tts, params, labels),
+                close_span: delimited.close_span
+            }))),
+            _ => result.push(tt.clone())
+        }
+    }
+    result
 }
 
 const PREFIXES: [&'static str; 12] = [
@@ -426,7 +1119,7 @@ fn eat_pseudo_keyword(parser: &mut Parser, kw: &str) -> bool {
     true
 }
 
-fn parse_arg<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
+fn parse_arg<'a>(ecx: &ExtCtxt, aliases: &Aliases, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
     use syntax::ast::ExprKind;
 
     // sizehint
@@ -485,29 +1178,32 @@ fn parse_arg<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
     }
 
     // it's a normal (register/immediate/memoryref/typemapped) operand
-    try!(parser.parse_expr()).and_then(|arg| {
+    let (arg, field_size) = fold_symbols(ecx, aliases, try!(parser.parse_expr()));
+    arg.and_then(|arg| {
         // typemapped
         if parser.eat(&token::FatArrow) {
-            let base = parse_reg(ecx, &arg);
+            let base = parse_reg(ecx, aliases, &arg);
             if base.is_none() {
                 ecx.span_err(arg.span, "Expected register");
                 return Ok(Arg::Invalid);
             }
 
+            // structures defined by .struct are marked as such, so they can't be confused with Rust types
+            let is_struct = eat_pseudo_keyword(parser, "struct");
             let ty = try!(parser.parse_path(PathStyle::Type));
 
-            // any attribute, register as index and immediate in index
-            let mut attr = None;
+            // any attributes, register as index and immediate in index
+            let mut attrs = Vec::new();
             let mut index_reg = None;
             let mut index_disp = None;
 
             if parser.eat(&token::OpenDelim(token::DelimToken::Bracket)) {
-                let index_expr = try!(parser.parse_expr());
+                let (index_expr, _) = fold_symbols(ecx, aliases, try!(parser.parse_expr()));
                 let span = index_expr.span;
 
                 try!(parser.expect(&token::CloseDelim(token::DelimToken::Bracket)));
 
-                let (mut regs, disp) = parse_adds(ecx, span, index_expr);
+                let (mut regs, disp) = parse_adds(ecx, aliases, span, index_expr);
                 index_disp = disp;
 
                 if regs.len() > 1 {
@@ -523,54 +1219,92 @@ fn parse_arg<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
                     index_reg = Some(reg)
                 }
             }
-            if parser.eat(&token::Dot) {
-                attr = Some(try!(parser.parse_ident()));
+            while parser.eat(&token::Dot) {
+                try!(parse_typemap_field(parser, &mut attrs));
             }
 
-            // attribute offset calculation
-            let attr_disp = attr.map(|attr| offset_of(ecx, ty.clone(), attr));
-            // scale calculation
-            let scale = if index_reg.is_some() {
-                Some(size_of(ecx, ty.clone()))
-            } else {
-                None
+            let span = Span {hi: parser.prev_span.hi, ..start};
+
+            // the layout of primitive types and structures defined by .struct is known here, so
+            // their offsets, sizes and scales are constants. Otherwise code is generated to
+            // determine them.
+            let layout = match static_layout(ecx, &ty, &attrs, is_struct) {
+                Ok(layout) => layout,
+                Err(()) => return Ok(Arg::Invalid)
             };
-            // joining index_disp and attr_disp into disp (attr_disp + (sizeof<ty>() as i32) * index_disp)
-            let disp = if let Some(index_disp) = index_disp {
-                let span = index_disp.span;
-                let index_disp = size_of_scale_expr(ecx, ty, index_disp);
-                Some(if let Some(attr_disp) = attr_disp {
-                    ecx.expr_binary(span,
-                        ast::BinOpKind::Add,
-                        attr_disp,
-                        index_disp
-                    )
+
+            let (attr_disp, index_disp, scale, scale_expr, size, size_expr) = if let Some((type_size, offset, field_size)) = layout {
+                let attr_disp = if attrs.is_empty() {
+                    None
                 } else {
-                    index_disp
-                })
+                    Some(expr_i64(ecx, span, offset as i64))
+                };
+                let index_disp = index_disp.map(|index_disp| {
+                    ecx.expr_binary(index_disp.span, ast::BinOpKind::Mul, expr_i64(ecx, span, type_size as i64), index_disp)
+                });
+                // sizes that can't be encoded as a scale are left to the compiler
+                let (scale, scale_expr) = match type_size {
+                    1 | 2 | 4 | 8 => (type_size as isize, None),
+                    _ => (8, Some(ecx.expr_usize(span, type_size as usize)))
+                };
+                (attr_disp, index_disp, scale, scale_expr, size.or(field_size), None)
             } else {
-                attr_disp
+                // attribute offset calculation
+                let attr_disp = if attrs.is_empty() {
+                    None
+                } else {
+                    Some(offset_of(ecx, ty.clone(), &attrs))
+                };
+                // (sizeof<ty>() as i32) * index_disp
+                let index_disp = index_disp.map(|index_disp| size_of_scale_expr(ecx, ty.clone(), index_disp));
+                // the size of the accessed field or element, in case no size is given
+                let size_expr = if attrs.is_empty() {
+                    size_of(ecx, ty.clone())
+                } else {
+                    size_of_field(ecx, ty.clone(), &attrs)
+                };
+                // scale is set to 8 as to avoid optimizations by the compiler
+                (attr_disp, index_disp, 8, Some(size_of(ecx, ty)), size, Some(size_expr))
+            };
+
+            // joining index_disp and attr_disp into disp
+            let disp = match (attr_disp, index_disp) {
+                (Some(attr_disp), Some(index_disp)) => Some(ecx.expr_binary(index_disp.span, ast::BinOpKind::Add, attr_disp, index_disp)),
+                (attr_disp, None) => attr_disp,
+                (None, index_disp) => index_disp
             };
 
             return Ok(Arg::Indirect(MemoryRef {
+                scale:      if index_reg.is_some() {scale} else {8},
+                scale_expr: if index_reg.is_some() {scale_expr} else {None},
                 index:      index_reg,
-                scale:      8, // scale is set to 8 as to avoid optimizations by the compiler
-                scale_expr: scale,
                 base:       base.map(|s| s.node),
                 disp:       disp,
                 size:       size,
-                span:       Span {hi: parser.prev_span.hi, ..start}
+                size_expr:  size_expr,
+                span:       span
             }));
         }
 
         // direct register reference
-        if let Some(reg) = parse_reg(ecx, &arg) {
+        if let Some(reg) = parse_reg(ecx, aliases, &arg) {
             if size.is_some() {
                 ecx.span_err(arg.span, "size hint with direct register");
             }
             return Ok(Arg::Direct(reg))
         }
 
+        // aliased memory reference. A size hint at the use overrides the size of the alias
+        if let Some(name) = as_simple_name(&arg) {
+            if let Some(AliasValue::Memory(mut mem)) = aliases.lookup(ecx, name.node.name) {
+                if size.is_some() {
+                    mem.size = size;
+                }
+                mem.span = arg.span;
+                return Ok(Arg::Indirect(mem));
+            }
+        }
+
         // memory location
         if let ast::Expr {node: ExprKind::Vec(mut items), span, ..} = arg {
             if items.len() != 1 {
@@ -578,7 +1312,7 @@ fn parse_arg<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
                 return Ok(Arg::Invalid);
             }
 
-            let (mut regs, disp) = parse_adds(ecx, span, items.pop().unwrap());
+            let (mut regs, disp) = parse_adds(ecx, aliases, span, items.pop().unwrap());
 
             // can only have two regs at most
             if regs.len() > 2 {
@@ -601,14 +1335,16 @@ fn parse_arg<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
                 }
             };
 
-            // assemble the memory location
+            // assemble the memory location. Without a size hint, the size of a referenced
+            // structure field is used
             return Ok(Arg::Indirect(MemoryRef {
                 index:      index,
                 scale:      scale,
                 scale_expr: None,
                 base:       base,
                 disp:       disp,
-                size:       size,
+                size:       size.or(field_size),
+                size_expr:  None,
                 span:       span
             }));
         }
@@ -618,6 +1354,108 @@ fn parse_arg<'a>(ecx: &ExtCtxt, parser: &mut Parser<'a>) -> PResult<'a, Arg> {
     })
 }
 
+// returns the size of the type of a type map, followed by the offset and size of the accessed
+// attribute, if these are known while parsing. This is the case for primitive types and
+// structures defined by .struct, which are used in a type map as `struct Name`.
+fn static_layout(ecx: &ExtCtxt, ty: &ast::Path, attrs: &[TypeMapField], is_struct: bool) -> Result<Option<(u64, u64, Option<Size>)>, ()> {
+    if ty.global || ty.segments.len() != 1 || !ty.segments[0].parameters.is_empty() {
+        if is_struct {
+            ecx.span_err(ty.span, "Expected the name of a structure defined by .struct");
+            return Err(());
+        }
+        return Ok(None);
+    }
+    let name = ty.segments[0].identifier.name;
+
+    if is_struct {
+        return struct_layout(ecx, ty.span, name, attrs).map(Some);
+    }
+
+    let primitive = match &*name.as_str() {
+        "u8"  | "i8"  | "bool"                => Some(Size::BYTE),
+        "u16" | "i16"                         => Some(Size::WORD),
+        "u32" | "i32" | "f32" | "char"        => Some(Size::DWORD),
+        "u64" | "i64" | "f64" | "usize" | "isize" => Some(Size::QWORD),
+        _ => None
+    };
+    if let Some(size) = primitive {
+        // primitives have no attributes, which is left to rustc to report
+        if !attrs.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some((size.in_bytes() as u64, 0, Some(size))));
+    }
+    Ok(None)
+}
+
+// returns the size of a structure defined by .struct, followed by the offset and size of the
+// accessed attribute.
+fn struct_layout(ecx: &ExtCtxt, span: Span, name: ast::Name, attrs: &[TypeMapField]) -> Result<(u64, u64, Option<Size>), ()> {
+    let global_data = super::crate_local_data(ecx);
+    let lock = global_data.read();
+    let structure = match lock.structs.get(&name) {
+        Some(structure) => structure,
+        None => {
+            ecx.span_err(span, &format!("Unknown structure '{}'", name.as_str()));
+            return Err(());
+        }
+    };
+    let type_size = structure.size;
+
+    // the structure the next attribute is looked up in
+    let mut structure = Some(structure);
+
+    let mut offset = 0;
+    let mut field_size = None;
+    for attr in attrs {
+        let ident = match *attr {
+            TypeMapField::Named(ident) => ident,
+            TypeMapField::Positional(index) => {
+                ecx.span_err(index.span, "Structures defined by .struct have no positional fields");
+                return Err(());
+            }
+        };
+        let field = match structure.and_then(|s| s.fields.get(&ident.node.name)) {
+            Some(field) => field,
+            None => {
+                ecx.span_err(ident.span, &format!("No field '{}' in this structure", ident.node.name.as_str()));
+                return Err(());
+            }
+        };
+        offset += field.offset;
+        field_size = field.size;
+        structure = field.structure.map(|s| &lock.structs[&s]);
+    }
+
+    Ok((type_size, offset, field_size))
+}
+
+// parses a field of a type map path after a dot. `Type.0.1` is lexed as a float literal.
+fn parse_typemap_field<'a>(parser: &mut Parser<'a>, attrs: &mut Vec<TypeMapField>) -> PResult<'a, ()> {
+    let span = parser.span;
+    let literal = match parser.token {
+        token::Literal(token::Integer(name), None) |
+        token::Literal(token::Float(name), None) => Some(name),
+        _ => None
+    };
+
+    let literal = if let Some(literal) = literal {
+        literal
+    } else {
+        attrs.push(TypeMapField::Named(Spanned {node: try!(parser.parse_ident()), span: span}));
+        return Ok(());
+    };
+
+    parser.bump();
+    for index in literal.as_str().split('.') {
+        match index.parse() {
+            Ok(index) => attrs.push(TypeMapField::Positional(Spanned {node: index, span: span})),
+            Err(_) => return Err(parser.span_fatal(span, "Invalid tuple field"))
+        }
+    }
+    Ok(())
+}
+
 pub fn as_simple_name(expr: &ast::Expr) -> Option<Ident> {
     let path = match *expr {
         ast::Expr {node: ast::ExprKind::Path(None, ref path) , ..} => path,
@@ -636,7 +1474,15 @@ pub fn as_simple_name(expr: &ast::Expr) -> Option<Ident> {
     Some(Ident {node: segment.identifier, span: path.span})
 }
 
-fn parse_reg(ecx: &ExtCtxt, expr: &ast::Expr) -> Option<Spanned<Register>> {
+// returns if expr is the name of a register or an alias
+fn is_register_or_alias(ecx: &ExtCtxt, aliases: &Aliases, expr: &ast::Expr) -> bool {
+    match as_simple_name(expr) {
+        Some(name) => aliases.lookup(ecx, name.node.name).is_some() || parse_reg(ecx, aliases, expr).is_some(),
+        None => false
+    }
+}
+
+fn parse_reg(ecx: &ExtCtxt, aliases: &Aliases, expr: &ast::Expr) -> Option<Spanned<Register>> {
     if let Some(path) = as_simple_name(expr) {
         // static register names
         use self::RegId::*;
@@ -695,14 +1541,12 @@ fn parse_reg(ecx: &ExtCtxt, expr: &ast::Expr) -> Option<Spanned<Register>> {
             "dr8"  => (DR8 , QWORD), "dr9"  => (DR9 , QWORD), "dr10" => (DR10, QWORD), "dr11" => (DR11, QWORD),
             "dr12" => (DR12, QWORD), "dr13" => (DR13, QWORD), "dr14" => (DR14, QWORD), "dr15" => (DR15, QWORD),
 
-            _ => {
-                let global_data = super::crate_local_data(ecx);
-                let lock = global_data.read();
-                if let Some(x) = lock.aliases.get(&path.node.name) {
-                    *x
-                } else {
-                    return None;
-                }
+            _ => return match aliases.lookup(ecx, path.node.name) {
+                Some(AliasValue::Register(reg)) => Some(Spanned {
+                    node: reg,
+                    span: path.span
+                }),
+                _ => None
             }
         };
 
@@ -749,7 +1593,7 @@ fn parse_reg(ecx: &ExtCtxt, expr: &ast::Expr) -> Option<Spanned<Register>> {
     }
 }
 
-fn parse_adds(ecx: &ExtCtxt, span: Span, expr: P<ast::Expr>) -> (Vec<(Register, isize)>, Option<P<ast::Expr>>) {
+fn parse_adds(ecx: &ExtCtxt, aliases: &Aliases, span: Span, expr: P<ast::Expr>) -> (Vec<(Register, isize)>, Option<P<ast::Expr>>) {
     use syntax::ast::ExprKind;
 
     let mut exprs = Vec::new();
@@ -763,7 +1607,7 @@ fn parse_adds(ecx: &ExtCtxt, span: Span, expr: P<ast::Expr>) -> (Vec<(Register,
     // static reg combiner. we do not combine dynamic regs as the equation used to construct them might have side effects.
     for node in exprs {
         // simple reg
-        if let Some(Spanned {node: reg, ..} ) = parse_reg(ecx, &node) {
+        if let Some(Spanned {node: reg, ..} ) = parse_reg(ecx, aliases, &node) {
             match reg.kind {
                 RegKind::Static(id) => *static_regs.entry((id, reg.size)).or_insert(0) += 1 as isize,
                 RegKind::Dynamic(_, _) => regs.push((reg, 1))
@@ -772,7 +1616,7 @@ fn parse_adds(ecx: &ExtCtxt, span: Span, expr: P<ast::Expr>) -> (Vec<(Register,
         }
         if let ast::Expr {node: ExprKind::Binary(ast::BinOp {node: ast::BinOpKind::Mul, ..}, ref left, ref right), ..} = *node {
             // reg * const
-            if let Some(Spanned {node: reg, ..} ) = parse_reg(ecx, left) {
+            if let Some(Spanned {node: reg, ..} ) = parse_reg(ecx, aliases, left) {
                 if let ast::Expr {node: ExprKind::Lit(ref scale), ..} = **right {
                     if let ast::LitKind::Int(value, _) = scale.node {
                         match reg.kind {
@@ -783,7 +1627,7 @@ fn parse_adds(ecx: &ExtCtxt, span: Span, expr: P<ast::Expr>) -> (Vec<(Register,
                     }
                 }
             // const * reg
-            } else if let Some(Spanned {node: reg, ..} ) = parse_reg(ecx, right) {
+            } else if let Some(Spanned {node: reg, ..} ) = parse_reg(ecx, aliases, right) {
                 if let ast::Expr {node: ExprKind::Lit(ref scale), ..} = **left {
                     if let ast::LitKind::Int(value, _) = scale.node {
                         match reg.kind {
@@ -824,3 +1668,131 @@ fn collect_adds(ecx: &ExtCtxt, node: P<ast::Expr>, collection: &mut Vec<P<ast::E
         }
     })
 }
+
+/// Replaces the names of constants defined by `.equ` and the fields of structures defined by
+/// `.struct` in `expr` by their values. If exactly one field was referenced, its size is
+/// returned as well. Names of registers and aliases are left alone, so they can be recognized
+/// afterwards. Constants do shadow any Rust variables with the same name.
+pub fn fold_symbols(ecx: &ExtCtxt, aliases: &Aliases, expr: P<ast::Expr>) -> (P<ast::Expr>, Option<Size>) {
+    let global_data = super::crate_local_data(ecx);
+    let lock = global_data.read();
+    if lock.constants.is_empty() && lock.structs.is_empty() {
+        return (expr, None);
+    }
+
+    let mut folder = SymbolFolder {
+        ecx: ecx,
+        aliases: aliases,
+        data: &*lock,
+        fields: Vec::new()
+    };
+    let expr = folder.fold_expr(expr);
+    let size = if folder.fields.len() == 1 { folder.fields[0] } else { None };
+    (expr, size)
+}
+
+struct SymbolFolder<'a, 'b: 'a> {
+    ecx: &'a ExtCtxt<'b>,
+    aliases: &'a Aliases,
+    data: &'a super::DynasmData,
+    // the sizes of the fields that were referenced
+    fields: Vec<Option<Size>>
+}
+
+impl<'a, 'b> SymbolFolder<'a, 'b> {
+    // resolves Struct.field.field.. to its offset, size and structure
+    fn field(&self, expr: &ast::Expr) -> Option<(u64, Option<Size>, Option<ast::Name>)> {
+        let (base, field) = match expr.node {
+            ast::ExprKind::Field(ref base, ref field) => (base, field),
+            _ => return None
+        };
+
+        let (offset, structure) = if let Some(name) = as_simple_name(base) {
+            if !self.data.structs.contains_key(&name.node.name) {
+                return None;
+            }
+            (0, name.node.name)
+        } else {
+            match self.field(base) {
+                Some((offset, _, Some(structure))) => (offset, structure),
+                _ => return None
+            }
+        };
+
+        match self.data.structs[&structure].fields.get(&field.node.name) {
+            Some(f) => Some((offset + f.offset, f.size, f.structure)),
+            None => {
+                self.ecx.span_err(field.span, &format!("Structure '{}' has no field '{}'", structure.as_str(), field.node.name.as_str()));
+                None
+            }
+        }
+    }
+}
+
+impl<'a, 'b> Folder for SymbolFolder<'a, 'b> {
+    fn fold_expr(&mut self, expr: P<ast::Expr>) -> P<ast::Expr> {
+        let span = expr.span;
+
+        if let Some(name) = as_simple_name(&expr) {
+            if let Some(&value) = self.data.constants.get(&name.node.name) {
+                if !is_register_or_alias(self.ecx, self.aliases, &expr) {
+                    return expr_i64(self.ecx, span, value);
+                }
+            }
+        }
+
+        if let Some((offset, size, _)) = self.field(&expr) {
+            self.fields.push(size);
+            return expr_i64(self.ecx, span, offset as i64);
+        }
+
+        expr.map(|e| fold::noop_fold_expr(e, self))
+    }
+
+    // macro invocations are left alone
+    fn fold_mac(&mut self, mac: ast::Mac) -> ast::Mac {
+        mac
+    }
+}
+
+fn expr_i64(ecx: &ExtCtxt, span: Span, value: i64) -> P<ast::Expr> {
+    let lit = ecx.expr_lit(span, ast::LitKind::Int(value.wrapping_abs() as u64, ast::LitIntType::Unsuffixed));
+    if value < 0 {
+        ecx.expr_unary(span, ast::UnOp::Neg, lit)
+    } else {
+        lit
+    }
+}
+
+/// Evaluates an integer expression consisting of literals and arithmetic at compile time.
+pub fn eval_const(expr: &ast::Expr) -> Option<i64> {
+    match expr.node {
+        ast::ExprKind::Lit(ref lit) => match lit.node {
+            ast::LitKind::Int(value, _) => Some(value as i64),
+            _ => None
+        },
+        ast::ExprKind::Paren(ref inner) => eval_const(inner),
+        ast::ExprKind::Unary(ast::UnOp::Neg, ref inner) => eval_const(inner).map(|v| v.wrapping_neg()),
+        ast::ExprKind::Unary(ast::UnOp::Not, ref inner) => eval_const(inner).map(|v| !v),
+        ast::ExprKind::Binary(op, ref left, ref right) => {
+            let (left, right) = match (eval_const(left), eval_const(right)) {
+                (Some(left), Some(right)) => (left, right),
+                _ => return None
+            };
+            match op.node {
+                ast::BinOpKind::Add    => Some(left.wrapping_add(right)),
+                ast::BinOpKind::Sub    => Some(left.wrapping_sub(right)),
+                ast::BinOpKind::Mul    => Some(left.wrapping_mul(right)),
+                ast::BinOpKind::Div    => left.checked_div(right),
+                ast::BinOpKind::Rem    => left.checked_rem(right),
+                ast::BinOpKind::BitAnd => Some(left & right),
+                ast::BinOpKind::BitOr  => Some(left | right),
+                ast::BinOpKind::BitXor => Some(left ^ right),
+                ast::BinOpKind::Shl    => Some(left.wrapping_shl(right as u32)),
+                ast::BinOpKind::Shr    => Some(left.wrapping_shr(right as u32)),
+                _ => None
+            }
+        },
+        _ => None
+    }
+}
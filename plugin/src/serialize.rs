@@ -1,7 +1,7 @@
 use std::rc::Rc;
 
 use compiler;
-use parser::{Size};
+use parser::{Size, TypeMapField};
 
 use syntax::ext::build::AstBuilder;
 use syntax::ext::base::ExtCtxt;
@@ -48,9 +48,27 @@ pub fn serialize(ecx: &mut ExtCtxt, name: P<ast::Expr>, stmts: compiler::StmtBuf
             Var(expr, Size::WORD)  => ("push_i16", vec![expr]),
             Var(expr, Size::DWORD) => ("push_i32", vec![expr]),
             Var(expr, Size::QWORD) => ("push_i64", vec![expr]),
-            Var(_, _)           => panic!("immediate serializaiton of this size is not supported yet"),
+            Var(expr, Size::OWORD) => ("push_u128", vec![expr]),
+            Var(expr, Size::HWORD) => ("push_u256", vec![expr]),
+            Var(_, _)              => panic!("immediate serialization of this size is not supported"),
+
+            Float(expr, Size::DWORD) => ("push_f32", vec![expr]),
+            Float(expr, Size::QWORD) => ("push_f64", vec![expr]),
+            Float(expr, Size::PWORD) => ("push_f80", vec![expr]),
+            Float(_, _)              => panic!("floating point serialization of this size is not supported"),
 
             Extend(expr)           => ("extend", vec![expr]),
+            RuntimeError(span, msg) => ("runtime_error", vec![ecx.expr_str(span, intern(msg).as_str())]),
+            Fill(count, size, value) => {
+                // both are evaluated before the binding is visible, count first
+                let span = ecx.call_site();
+                let (count_span, value_span) = (count.span, value.span);
+                let temp = ast::Ident::with_empty_ctxt(intern("temp"));
+                buffer.push(ecx.stmt_let(span, false, temp, ecx.expr_tuple(span, vec![count, value])));
+                let count = ecx.expr_tup_field_access(count_span, ecx.expr_ident(span, temp), 0);
+                let value = ecx.expr_tup_field_access(value_span, ecx.expr_ident(span, temp), 1);
+                ("extend", vec![fill_expr(ecx, count, size, value)])
+            },
 
             DynScale(scale, rest)  => {
                 let temp = ast::Ident::with_empty_ctxt(intern("temp"));
@@ -59,6 +77,7 @@ pub fn serialize(ecx: &mut ExtCtxt, name: P<ast::Expr>, stmts: compiler::StmtBuf
             },
 
             Align(expr)            => ("align",   vec![expr]),
+            AlignPatchable(size, offset) => ("align_patchable", vec![size, offset]),
 
             GlobalLabel(ident)     => ("global_label", vec![ecx.expr_lit(
                 ident.span,
@@ -86,6 +105,51 @@ pub fn serialize(ecx: &mut ExtCtxt, name: P<ast::Expr>, stmts: compiler::StmtBuf
                 let span = expr.span;
                 ("dynamic_reloc", vec![expr, ecx.expr_u8(span, size.in_bytes())])
             },
+            JumpPatchPoint(expr, size) => {
+                let span = expr.span;
+                ("jump_patch_point", vec![expr, ecx.expr_u8(span, size.in_bytes())])
+            },
+            ImmediatePatchPoint(expr, size) => {
+                let span = expr.span;
+                ("immediate_patch_point", vec![expr, ecx.expr_u8(span, size.in_bytes())])
+            },
+            CfiStartProc           => ("cfi_start_proc", Vec::new()),
+            CfiEndProc             => ("cfi_end_proc", Vec::new()),
+            CfiDefCfa(reg, offset) => ("cfi_def_cfa", vec![ecx.expr_u8(ecx.call_site(), reg), offset]),
+            CfiDefCfaOffset(offset) => ("cfi_def_cfa_offset", vec![offset]),
+            CfiDefCfaRegister(reg) => ("cfi_def_cfa_register", vec![ecx.expr_u8(ecx.call_site(), reg)]),
+            CfiOffset(reg, offset) => ("cfi_offset", vec![ecx.expr_u8(ecx.call_site(), reg), offset]),
+            CfiRememberState       => ("cfi_remember_state", Vec::new()),
+            CfiRestoreState        => ("cfi_restore_state", Vec::new()),
+
+            SourceLocation(span, mnemonic) => {
+                let loc = ecx.codemap().lookup_char_pos(span.lo);
+                ("source_location", vec![
+                    ecx.expr_str(span, intern(&loc.file.name).as_str()),
+                    ecx.expr_u32(span, loc.line as u32),
+                    ecx.expr_u32(span, loc.col.0 as u32 + 1),
+                    ecx.expr_lit(mnemonic.span, ast::LitKind::Str(mnemonic.node.name.as_str(), ast::StrStyle::Cooked))
+                ])
+            },
+            SourceLocationEnd => ("source_location_end", Vec::new()),
+
+            // if c1 { .. } else if c2 { .. } else { .. }, built from the last branch backwards
+            Conditional(branches, otherwise) => {
+                let mut chain = if otherwise.is_empty() {
+                    None
+                } else {
+                    let block = serialize(ecx, name.clone(), otherwise);
+                    Some(ecx.expr_block(ecx.block(ecx.call_site(), block)))
+                };
+                for (condition, body) in branches.into_iter().rev() {
+                    let block = serialize(ecx, name.clone(), body);
+                    let then = ecx.expr_block(ecx.block(ecx.call_site(), block));
+                    chain = Some(ecx.expr_if(condition.span, condition, then, chain));
+                }
+                buffer.push(ecx.stmt_expr(chain.unwrap()));
+                continue;
+            },
+
             Stmt(stmt) => {
                 buffer.push(stmt);
                 continue;
@@ -123,6 +187,32 @@ pub fn size_of_scale_expr(ecx: &ExtCtxt, ty: ast::Path, value: P<ast::Expr>) ->
     )
 }
 
+// generate size == 1 || size == 2 || size == 4 || size == 8
+pub fn encodable_scale(ecx: &ExtCtxt, span: Span, size: P<ast::Expr>) -> P<ast::Expr> {
+    [1, 2, 4, 8].iter()
+        .map(|&scale| ecx.expr_binary(span, ast::BinOpKind::Eq, size.clone(), ecx.expr_usize(span, scale)))
+        .fold(None, |accum, next| Some(match accum {
+            Some(accum) => ecx.expr_binary(span, ast::BinOpKind::Or, accum, next),
+            None => next
+        })).unwrap()
+}
+
+// generate a statement that only compiles if size can be encoded as a scale:
+// { const DYNASM_SCALE_CHECK: [(); 1] = [(); (size == 1 || size == 2 || size == 4 || size == 8) as usize]; }
+// the block keeps the constants of different instructions apart.
+pub fn scale_assertion(ecx: &ExtCtxt, span: Span, size: P<ast::Expr>) -> ast::Stmt {
+    let usize_id = ast::Ident::with_empty_ctxt(intern("usize"));
+    let count = ecx.expr_cast(span, encodable_scale(ecx, span, size), ecx.ty_ident(span, usize_id));
+
+    let unit = ecx.ty(span, ast::TyKind::Tup(Vec::new()));
+    let ty = ecx.ty(span, ast::TyKind::FixedLengthVec(unit, ecx.expr_usize(span, 1)));
+    let value = ecx.expr(span, ast::ExprKind::Repeat(ecx.expr_tuple(span, Vec::new()), count));
+
+    let name = ast::Ident::with_empty_ctxt(intern("DYNASM_SCALE_CHECK"));
+    let item = ecx.stmt_item(span, ecx.item_const(span, name, ty, value));
+    ecx.stmt_expr(ecx.expr_block(ecx.block(span, vec![item])))
+}
+
 pub fn or_mask_shift_expr(ecx: &ExtCtxt, orig: P<ast::Expr>, mut expr: P<ast::Expr>, mask: u64, shift: i8) -> P<ast::Expr> {
     let span = expr.span;
     // take expr and return !((expr & mask) << shift)
@@ -144,82 +234,114 @@ pub fn or_mask_shift_expr(ecx: &ExtCtxt, orig: P<ast::Expr>, mut expr: P<ast::Ex
     ecx.expr_binary(span, ast::BinOpKind::BitOr, orig, expr)
 }
 
-pub fn offset_of(ecx: &ExtCtxt, path: ast::Path, attr: ast::Ident) -> P<ast::Expr> {
+pub fn offset_of(ecx: &ExtCtxt, path: ast::Path, fields: &[TypeMapField]) -> P<ast::Expr> {
     // generate a P<Expr> that resolves into the offset of an attribute to a type.
     // this is somewhat ridiculously complex because we can't expand macros here
 
     let span = path.span;
+    let usize_id = ast::Ident::with_empty_ctxt(intern("usize"));
 
-    let structpat = ecx.pat_struct(span, path.clone(), vec![
-        Spanned {span: span, node: ast::FieldPat {
-            ident: attr,
-            pat: ecx.pat_wild(span),
-            is_shorthand: false
-        }},
-    ]).map(|mut pat| {
-        if let ast::PatKind::Struct(_, _, ref mut dotdot) = pat.node {
-            *dotdot = true;
-        }
-        pat
-    });
+    // &temp.fields as *const _ as usize - &temp as *const _ as usize
+    field_expr(ecx, path, fields, |temp, field| ecx.expr_binary(span, ast::BinOpKind::Sub,
+        ecx.expr_cast(span,
+            ecx.expr_cast(span,
+                ecx.expr_addr_of(span, field),
+                ecx.ty_ptr(span, ecx.ty_infer(span), ast::Mutability::Immutable)
+            ), ecx.ty_ident(span, usize_id)
+        ),
+        ecx.expr_cast(span,
+            ecx.expr_cast(span,
+                ecx.expr_addr_of(span, temp),
+                ecx.ty_ptr(span, ecx.ty_infer(span), ast::Mutability::Immutable)
+            ), ecx.ty_ident(span, usize_id)
+        )
+    ), true)
+}
 
-    // there's no default constructor function for let pattern;
-    let validation_stmt = ast::Stmt {
-        id: ast::DUMMY_NODE_ID,
-        span: span,
-        node: ast::StmtKind::Local(P(ast::Local {
-            pat: structpat,
-            ty: None,
-            init: None,
-            id: ast::DUMMY_NODE_ID,
-            span: span,
-            attrs: ast::ThinVec::new()
-        }))
-    };
+pub fn size_of_field(ecx: &ExtCtxt, path: ast::Path, fields: &[TypeMapField]) -> P<ast::Expr> {
+    // generate a P<Expr> that resolves into the size of an attribute of a type
+    let span = path.span;
+    let size_of_val = ["std", "mem", "size_of_val"].iter().cloned().map(intern).map(ast::Ident::with_empty_ctxt).collect();
+
+    // ::std::mem::size_of_val(&temp.fields)
+    field_expr(ecx, path, fields, |_, field| {
+        ecx.expr_call_global(span, size_of_val, vec![ecx.expr_addr_of(span, field)])
+    }, false)
+}
+
+// generates an unsafe block that evaluates `value` with an uninitialized value of the type at
+// `path` and an access of `fields` in it. The result is cast to i32 if `to_i32` is set.
+fn field_expr<F>(ecx: &ExtCtxt, path: ast::Path, fields: &[TypeMapField], value: F, to_i32: bool) -> P<ast::Expr>
+where F: FnOnce(P<ast::Expr>, P<ast::Expr>) -> P<ast::Expr> {
+    let span = path.span;
 
     let temp     = ast::Ident::with_empty_ctxt(intern("temp"));
     let rv       = ast::Ident::with_empty_ctxt(intern("rv"));
-    let usize_id = ast::Ident::with_empty_ctxt(intern("usize"));
     let i32_id   = ast::Ident::with_empty_ctxt(intern("i32"));
     let uninitialized = ["std", "mem", "uninitialized"].iter().cloned().map(intern).map(ast::Ident::with_empty_ctxt).collect();
     let forget        = ["std", "mem", "forget"       ].iter().cloned().map(intern).map(ast::Ident::with_empty_ctxt).collect();
 
-    // unsafe {
-    let block = ecx.block(span, vec![
+    let mut stmts = Vec::new();
+
+    // the first field is validated to be a field of the type itself, and not of something
+    // it derefs to
+    if let Some(&TypeMapField::Named(attr)) = fields.first() {
+        let structpat = ecx.pat_struct(span, path.clone(), vec![
+            Spanned {span: span, node: ast::FieldPat {
+                ident: attr.node,
+                pat: ecx.pat_wild(span),
+                is_shorthand: false
+            }},
+        ]).map(|mut pat| {
+            if let ast::PatKind::Struct(_, _, ref mut dotdot) = pat.node {
+                *dotdot = true;
+            }
+            pat
+        });
+
+        // there's no default constructor function for let pattern;
         // let path { attr: _, ..};
-        validation_stmt,
-        // let temp: path = ::std::mem::uninitialized();
-        ecx.stmt_let_typed(span, false, temp, ecx.ty_path(path),
-            ecx.expr_call_global(span, uninitialized, Vec::new())
-        ),
-        // let rv = &temp.attr as *const _ as usize - &temp as *const _ as usize;
-        ecx.stmt_let(span,
-            false,
-            rv,
-            ecx.expr_binary(span, ast::BinOpKind::Sub,
-                ecx.expr_cast(span,
-                    ecx.expr_cast(span,
-                        ecx.expr_addr_of(span,
-                            ecx.expr_field_access(span,
-                                ecx.expr_ident(span, temp),
-                                attr
-                            )
-                        ), ecx.ty_ptr(span, ecx.ty_infer(span), ast::Mutability::Immutable)
-                    ), ecx.ty_ident(span, usize_id)
-                ),
-                ecx.expr_cast(span,
-                    ecx.expr_cast(span,
-                        ecx.expr_addr_of(span, ecx.expr_ident(span, temp)),
-                        ecx.ty_ptr(span, ecx.ty_infer(span), ast::Mutability::Immutable)
-                    ), ecx.ty_ident(span, usize_id)
-                )
-            )
-        ),
-        // ::std::mem::forget(temp);
-        ecx.stmt_semi(ecx.expr_call_global(span, forget, vec![ecx.expr_ident(span, temp)])),
-        // rv as i32
-        ecx.stmt_expr(ecx.expr_cast(span, ecx.expr_ident(span, rv), ecx.ty_ident(span, i32_id)))
-    ]).map(|mut b| {
+        stmts.push(ast::Stmt {
+            id: ast::DUMMY_NODE_ID,
+            span: span,
+            node: ast::StmtKind::Local(P(ast::Local {
+                pat: structpat,
+                ty: None,
+                init: None,
+                id: ast::DUMMY_NODE_ID,
+                span: span,
+                attrs: ast::ThinVec::new()
+            }))
+        });
+    }
+
+    // let temp: path = ::std::mem::uninitialized();
+    stmts.push(ecx.stmt_let_typed(span, false, temp, ecx.ty_path(path),
+        ecx.expr_call_global(span, uninitialized, Vec::new())
+    ));
+
+    // temp.field.field..
+    let mut field = ecx.expr_ident(span, temp);
+    for f in fields {
+        field = match *f {
+            TypeMapField::Named(ident) => ecx.expr_field_access(ident.span, field, ident.node),
+            TypeMapField::Positional(index) => ecx.expr_tup_field_access(index.span, field, index.node)
+        };
+    }
+
+    // let rv = value;
+    stmts.push(ecx.stmt_let(span, false, rv, value(ecx.expr_ident(span, temp), field)));
+    // ::std::mem::forget(temp);
+    stmts.push(ecx.stmt_semi(ecx.expr_call_global(span, forget, vec![ecx.expr_ident(span, temp)])));
+    // rv as i32
+    stmts.push(ecx.stmt_expr(if to_i32 {
+        ecx.expr_cast(span, ecx.expr_ident(span, rv), ecx.ty_ident(span, i32_id))
+    } else {
+        ecx.expr_ident(span, rv)
+    }));
+
+    // unsafe {
+    let block = ecx.block(span, stmts).map(|mut b| {
         b.rules = ast::BlockCheckMode::Unsafe(ast::UnsafeSource::CompilerGenerated);
         b
     });
@@ -237,6 +359,37 @@ pub fn size_of(ecx: &ExtCtxt, path: ast::Path) -> P<ast::Expr> {
     ecx.expr_call(span, ecx.expr_path(size_of), Vec::new())
 }
 
+pub fn fill_expr(ecx: &ExtCtxt, count: P<ast::Expr>, size: u8, value: P<ast::Expr>) -> P<ast::Expr> {
+    // generate [value as u8, (value >> 8) as u8, ..].iter().cloned().cycle().take(count as usize * size)
+    let span = count.span;
+    let u8_id    = ast::Ident::with_empty_ctxt(intern("u8"));
+    let i64_id   = ast::Ident::with_empty_ctxt(intern("i64"));
+    let usize_id = ast::Ident::with_empty_ctxt(intern("usize"));
+
+    let value = ecx.expr_cast(span, value, ecx.ty_ident(span, i64_id));
+    let bytes = (0..size).map(|i| {
+        let shifted = if i == 0 {
+            value.clone()
+        } else {
+            ecx.expr_binary(span, ast::BinOpKind::Shr, value.clone(), ecx.expr_lit(
+                span, ast::LitKind::Int(i as u64 * 8, ast::LitIntType::Unsuffixed)
+            ))
+        };
+        ecx.expr_cast(span, shifted, ecx.ty_ident(span, u8_id))
+    }).collect();
+
+    let mut iter = ecx.expr_vec(span, bytes);
+    for method in &["iter", "cloned", "cycle"] {
+        iter = ecx.expr_method_call(span, iter, ast::Ident::with_empty_ctxt(intern(method)), Vec::new());
+    }
+
+    let len = ecx.expr_binary(span, ast::BinOpKind::Mul,
+        ecx.expr_cast(span, count, ecx.ty_ident(span, usize_id)),
+        ecx.expr_usize(span, size as usize)
+    );
+    ecx.expr_method_call(span, iter, ast::Ident::with_empty_ctxt(intern("take")), vec![len])
+}
+
 pub fn encoded_size(ecx: &ExtCtxt, name: &P<ast::Expr>, size: P<ast::Expr>) -> P<ast::Expr> {
     let span = size.span;
 
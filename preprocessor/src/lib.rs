@@ -0,0 +1,239 @@
+#![feature(rustc_private)]
+
+//! A source preprocessor for dynasm-rs, meant to be called from `build.rs`. It expands the
+//! `dynasm!` invocations in a file using the same parser, compiler and serializer as the
+//! plugin and writes out the result as plain Rust code, which only calls methods of the
+//! `DynasmApi` and `DynasmLabelApi` traits. The output can therefore be compiled by any
+//! toolchain, without the compiler plugin.
+//!
+//! The preprocessor itself is built on libsyntax like the plugin, so it needs the same nightly
+//! toolchain. As cargo builds build scripts with the toolchain of the crate being built, a
+//! build script calling it only works on that nightly. To build a crate with another toolchain,
+//! generate the output on the nightly toolchain, commit the generated file and include it
+//! from the crate instead of generating it in its build script.
+//!
+//! Two kinds of input are understood:
+//!
+//! - Rust source files (`.rs`). Every `dynasm!` invocation in statement or expression position
+//!   is replaced by a block containing the code it expands to. Invocations in item position
+//!   produce no code and are removed. Invocations inside the bodies of other macros are not
+//!   visible to the preprocessor and are left alone.
+//! - Stand-alone dynasm files (`.dasm`). These contain the contents of a single `dynasm!`
+//!   invocation, starting with the assembler expression. They are turned into a single block
+//!   expression, which can be used with `include!`.
+//!
+//! As there is no macro expansion step, macros used in places where dynasm needs to inspect
+//! their result (for instance in register or immediate operands that are evaluated at compile
+//! time) are not expanded. Macros in runtime expressions are copied into the output as is.
+//!
+//! The generated code for an invocation is kept on the same lines as the invocation, so line
+//! numbers in the output match those in the input.
+//!
+//! ```ignore
+//! // build.rs
+//! extern crate dynasm_build;
+//!
+//! use std::env;
+//! use std::path::Path;
+//!
+//! fn main() {
+//!     let out = env::var("OUT_DIR").unwrap();
+//!     dynasm_build::preprocess("src/jit.rs", Path::new(&out).join("jit.rs")).unwrap();
+//! }
+//! ```
+
+extern crate syntax;
+extern crate dynasm;
+
+use syntax::ast;
+use syntax::codemap::{Span, ExpnInfo, NameAndSpan, ExpnFormat};
+use syntax::ext::base::{ExtCtxt, DummyResolver};
+use syntax::ext::expand::ExpansionConfig;
+use syntax::parse::{self, ParseSess};
+use syntax::parse::token::intern;
+use syntax::print::pprust;
+use syntax::tokenstream::TokenTree;
+use syntax::visit::{self, Visitor};
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Preprocesses the file at `input` and writes the result to `output`. Files with a `.dasm`
+/// extension are treated as stand-alone dynasm files, all other files as Rust source. Cargo
+/// is told to rerun the build script when `input` changes.
+///
+/// Errors in the input are reported on stderr, after which an error of kind `InvalidData`
+/// is returned.
+pub fn preprocess<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> io::Result<()> {
+    let input = input.as_ref();
+    println!("cargo:rerun-if-changed={}", input.display());
+
+    let mut source = String::new();
+    File::open(input)?.read_to_string(&mut source)?;
+
+    let name = input.display().to_string();
+    let result = if input.extension().map_or(false, |e| e == "dasm") {
+        expand_dasm(&name, &source)?
+    } else {
+        expand_rust(&name, &source)?
+    };
+
+    File::create(output)?.write_all(result.as_bytes())
+}
+
+/// Expands all `dynasm!` invocations in the Rust source `source`. `name` is used in
+/// diagnostics.
+pub fn expand_rust(name: &str, source: &str) -> io::Result<String> {
+    let sess = ParseSess::new();
+    let krate = match parse::parse_crate_from_source_str(name.to_string(), source.to_string(), Default::default(), &sess) {
+        Ok(krate) => krate,
+        Err(mut e) => {
+            e.emit();
+            return Err(failed(name));
+        }
+    };
+
+    // find all invocations
+    let mut finder = Finder {
+        invocations: Vec::new()
+    };
+    visit::walk_crate(&mut finder, &krate);
+    finder.invocations.sort_by_key(|i| i.span.lo);
+
+    // expand them, and splice the results into the source
+    let mut resolver = DummyResolver;
+    let mut ecx = ExtCtxt::new(&sess, Default::default(), ExpansionConfig::default("dynasm_build".to_string()), &mut resolver);
+
+    let mut result = String::new();
+    let mut pos = 0;
+    for invocation in finder.invocations {
+        let start = sess.codemap().lookup_byte_offset(invocation.span.lo).pos.0 as usize;
+        let end = sess.codemap().lookup_byte_offset(invocation.span.hi).pos.0 as usize;
+
+        let stmts = match expand(&mut ecx, invocation.span, &invocation.tts) {
+            Some(stmts) => stmts,
+            None => continue
+        };
+
+        result.push_str(&source[pos .. start]);
+        let original = &source[start .. end];
+        if invocation.item {
+            result.extend(original.chars().filter(|&c| c == '\n'));
+        } else {
+            result.push_str(&format_block(&stmts, original));
+        }
+        pos = end;
+    }
+    result.push_str(&source[pos ..]);
+
+    if sess.span_diagnostic.has_errors() {
+        return Err(failed(name));
+    }
+    Ok(result)
+}
+
+/// Expands the stand-alone dynasm file `source` into a block expression. `name` is used in
+/// diagnostics.
+pub fn expand_dasm(name: &str, source: &str) -> io::Result<String> {
+    let sess = ParseSess::new();
+    let mut p = parse::new_parser_from_source_str(&sess, Default::default(), name.to_string(), source.to_string());
+    let tts = match p.parse_all_token_trees() {
+        Ok(tts) => tts,
+        Err(mut e) => {
+            e.emit();
+            return Err(failed(name));
+        }
+    };
+    let span = match (tts.first(), tts.last()) {
+        (Some(first), Some(last)) => Span {lo: first.get_span().lo, hi: last.get_span().hi, expn_id: first.get_span().expn_id},
+        _ => return Ok("{}\n".to_string())
+    };
+
+    let mut resolver = DummyResolver;
+    let mut ecx = ExtCtxt::new(&sess, Default::default(), ExpansionConfig::default("dynasm_build".to_string()), &mut resolver);
+
+    let stmts = expand(&mut ecx, span, &tts);
+    match stmts {
+        Some(ref stmts) if !sess.span_diagnostic.has_errors() => Ok(format_block(stmts, source) + "\n"),
+        _ => Err(failed(name))
+    }
+}
+
+// expands a single invocation. Serialization generates code at the call site of the
+// current expansion, so one is pushed for the duration.
+fn expand(ecx: &mut ExtCtxt, span: Span, tts: &[TokenTree]) -> Option<Vec<ast::Stmt>> {
+    ecx.bt_push(ExpnInfo {
+        call_site: span,
+        callee: NameAndSpan {
+            format: ExpnFormat::MacroBang(intern("dynasm")),
+            allow_internal_unstable: false,
+            span: None
+        }
+    });
+    let stmts = dynasm::expand_tts(ecx, tts);
+    ecx.bt_pop();
+    stmts
+}
+
+// formats the statements as a block on a single line, followed by as many newlines as
+// `original` contains so the lines after it stay where they were.
+fn format_block(stmts: &[ast::Stmt], original: &str) -> String {
+    let mut block = String::from("{");
+    for stmt in stmts {
+        block.push(' ');
+        block.extend(pprust::stmt_to_string(stmt).chars().map(|c| if c == '\n' { ' ' } else { c }));
+    }
+    block.extend(original.chars().filter(|&c| c == '\n'));
+    block.push_str(" }");
+    block
+}
+
+fn failed(name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("could not preprocess '{}'", name))
+}
+
+struct Invocation {
+    // the span to replace
+    span: Span,
+    tts: Vec<TokenTree>,
+    // if the invocation is in item position, where it cannot be replaced by a block
+    item: bool
+}
+
+// collects all dynasm! invocations
+struct Finder {
+    invocations: Vec<Invocation>
+}
+
+fn is_dynasm(mac: &ast::Mac) -> bool {
+    let segments = &mac.node.path.segments;
+    segments.len() == 1 && &*segments[0].identifier.name.as_str() == "dynasm"
+}
+
+impl Visitor for Finder {
+    fn visit_item(&mut self, item: &ast::Item) {
+        if let ast::ItemKind::Mac(ref mac) = item.node {
+            if is_dynasm(mac) {
+                // the item span includes the trailing semicolon
+                self.invocations.push(Invocation {
+                    span: item.span,
+                    tts: mac.node.tts.clone(),
+                    item: true
+                });
+            }
+            return;
+        }
+        visit::walk_item(self, item);
+    }
+
+    fn visit_mac(&mut self, mac: &ast::Mac) {
+        if is_dynasm(mac) {
+            self.invocations.push(Invocation {
+                span: mac.span,
+                tts: mac.node.tts.clone(),
+                item: false
+            });
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use std::io::{self, Read, Write};
+use std::borrow::Cow;
+use std::mem;
+
+use super::{Assembler, PatchLoc, PatchKind, RelocKind};
+
+// file layout (all integers are little endian):
+// magic, version: u32, code length: u64, code,
+// global label count: u32, (name, offset: u64)*,
+// dynamic label count: u32, (offset: u64 or !0u64 if undefined)*,
+// external reloc count: u32, (end offset: u64, size: u8, kind: u8, name)*,
+// patch point count: u32, (kind: u8, (end offset: u64, size: u8) if recorded)*
+// where names are stored as length: u32, utf-8 bytes.
+
+const MAGIC: &'static [u8; 8] = b"DYNASMRT";
+const VERSION: u32 = 1;
+
+impl Assembler {
+    /// Commits all code and writes it to `w`, together with the global labels, dynamic labels,
+    /// patch points and references to external symbols. The result can be loaded with
+    /// `Assembler::load`, possibly in another process. As the code will be loaded at a different
+    /// address, any absolute addresses should only be encoded via `Assembler::extern_reloc`.
+    pub fn save<W: Write>(&mut self, w: &mut W) -> io::Result<()> {
+        self.commit();
+
+        w.write_all(MAGIC)?;
+        write_u32(w, VERSION)?;
+
+        {
+            let buf = self.execbuffer.read().unwrap();
+            write_u64(w, buf.length as u64)?;
+            w.write_all(&buf)?;
+        }
+
+        write_u32(w, self.labels.global_labels.len() as u32)?;
+        for (name, &offset) in &self.labels.global_labels {
+            write_str(w, name)?;
+            write_u64(w, offset as u64)?;
+        }
+
+        write_u32(w, self.labels.dynamic_labels.len() as u32)?;
+        for &offset in &self.labels.dynamic_labels {
+            write_u64(w, offset.map_or(!0u64, |offset| offset as u64))?;
+        }
+
+        write_u32(w, self.extern_relocs.len() as u32)?;
+        for &(ref loc, kind, ref name) in &self.extern_relocs {
+            write_u64(w, loc.0 as u64)?;
+            w.write_all(&[loc.1, match kind {
+                RelocKind::Relative => 0,
+                RelocKind::Absolute => 1,
+                RelocKind::Call => 2
+            }])?;
+            write_str(w, name)?;
+        }
+
+        write_u32(w, self.patch_points.len() as u32)?;
+        for point in &self.patch_points {
+            match *point {
+                None => w.write_all(&[0])?,
+                Some((ref loc, kind)) => {
+                    w.write_all(&[match kind {
+                        PatchKind::Jump => 1,
+                        PatchKind::Immediate => 2
+                    }])?;
+                    write_u64(w, loc.0 as u64)?;
+                    w.write_all(&[loc.1])?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates a new `Assembler` from code written by `Assembler::save`, and commits this code
+    /// to a new executable buffer. `resolve` is called once for every external symbol referenced
+    /// by the code to determine its address in the current process. Global labels can be looked
+    /// up using `Assembler::global_label_offset`. Dynamic labels and patch points keep their
+    /// identity: handles to them can be recreated from their ids using `DynamicLabel::from_raw`
+    /// and `PatchPoint::from_raw`, and dynamic labels and patch points created afterwards will
+    /// not clash with them.
+    pub fn load<R: Read, F>(r: &mut R, mut resolve: F) -> io::Result<Assembler>
+    where F: FnMut(&str) -> Option<*const u8> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a dynasmrt code cache"));
+        }
+        if read_u32(r)? != VERSION {
+            return Err(invalid("unsupported code cache version"));
+        }
+
+        let len = read_u64(r)?;
+        let code = read_bytes(r, len)?;
+        let len = code.len();
+
+        let mut assembler = Assembler::new();
+
+        for _ in 0..read_u32(r)? {
+            let name = read_string(r)?;
+            let offset = read_offset(r, len)?;
+            assembler.labels.global_labels.insert(Cow::Owned(name), offset);
+        }
+
+        for _ in 0..read_u32(r)? {
+            let offset = read_u64(r)?;
+            assembler.labels.dynamic_labels.push(if offset == !0u64 {
+                None
+            } else if offset as usize <= len {
+                Some(offset as usize)
+            } else {
+                return Err(invalid("label offset out of range"));
+            });
+        }
+
+        for _ in 0..read_u32(r)? {
+            let loc = read_patch_loc(r, len)?;
+            let kind = match read_u8(r)? {
+                0 => RelocKind::Relative,
+                1 => RelocKind::Absolute,
+                2 => RelocKind::Call,
+                _ => return Err(invalid("invalid relocation kind"))
+            };
+            let name = read_string(r)?;
+
+            if !assembler.extern_symbols.contains_key(&*name) {
+                if let Some(addr) = resolve(&name) {
+                    assembler.extern_symbols.insert(Cow::Owned(name.clone()), addr as usize);
+                } else {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("Unknown external symbol '{}'", name)));
+                }
+            }
+            assembler.extern_relocs.push((loc, kind, Cow::Owned(name)));
+        }
+
+        for _ in 0..read_u32(r)? {
+            assembler.patch_points.push(match read_u8(r)? {
+                0 => None,
+                1 => Some((read_patch_loc(r, len)?, PatchKind::Jump)),
+                2 => Some((read_patch_loc(r, len)?, PatchKind::Immediate)),
+                _ => return Err(invalid("invalid patch point kind"))
+            });
+        }
+
+        assembler.ops = code;
+        assembler.commit();
+        Ok(assembler)
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_u32<W: Write>(w: &mut W, value: u32) -> io::Result<()> {
+    w.write_all(unsafe { &mem::transmute::<_, [u8; 4]>(value.to_le()) })
+}
+
+fn write_u64<W: Write>(w: &mut W, value: u64) -> io::Result<()> {
+    w.write_all(unsafe { &mem::transmute::<_, [u8; 8]>(value.to_le()) })
+}
+
+fn write_str<W: Write>(w: &mut W, value: &str) -> io::Result<()> {
+    write_u32(w, value.len() as u32)?;
+    w.write_all(value.as_bytes())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le(unsafe { mem::transmute::<_, u32>(buf) }))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le(unsafe { mem::transmute::<_, u64>(buf) }))
+}
+
+// reads a buffer of the given length. The length comes from the cache itself, so the buffer
+// only grows as far as data is actually present instead of being allocated up front.
+fn read_bytes<R: Read>(r: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.by_ref().take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(invalid("truncated code cache"));
+    }
+    Ok(buf)
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = read_u32(r)?;
+    let buf = read_bytes(r, len as u64)?;
+    String::from_utf8(buf).map_err(|_| invalid("invalid symbol name"))
+}
+
+fn read_offset<R: Read>(r: &mut R, len: usize) -> io::Result<usize> {
+    let offset = read_u64(r)?;
+    if offset > len as u64 {
+        return Err(invalid("label offset out of range"));
+    }
+    Ok(offset as usize)
+}
+
+fn read_patch_loc<R: Read>(r: &mut R, len: usize) -> io::Result<PatchLoc> {
+    let offset = read_offset(r, len)?;
+    let size = read_u8(r)?;
+    match size {
+        1 | 2 | 4 | 8 if size as usize <= offset => Ok(PatchLoc(offset, size)),
+        _ => Err(invalid("invalid relocation size"))
+    }
+}
@@ -0,0 +1,673 @@
+use std::fmt::Write;
+
+use x64data::{self, Opdata, FormatStringIterator};
+use x64data::flags::*;
+
+// A disassembler driven by the same instruction tables the plugin uses for assembling. For every
+// instruction, all table entries are tried and the most specific one that fully matches the
+// encoded bytes is used to render the instruction back into dynasm syntax.
+
+// unwraps an option, returning None from the current function if it is empty
+macro_rules! try_opt {
+    ($e:expr) => (match $e {
+        Some(v) => v,
+        None => return None
+    })
+}
+
+lazy_static! {
+    // every (mnemnonic, encoding) pair, ordered by name so the output doesn't depend on hashing
+    static ref OPS: Vec<(&'static str, &'static Opdata)> = {
+        let mut names: Vec<&'static str> = x64data::mnemnonics().cloned().collect();
+        names.sort();
+        let mut ops = Vec::new();
+        for name in names {
+            for data in x64data::get_mnemnonic_data(name).unwrap() {
+                ops.push((name, data));
+            }
+        }
+        ops
+    };
+}
+
+/// Renders the instructions in `code[start .. end]` as a listing with a line per instruction,
+/// containing its offset, the encoded bytes and the instruction itself. `labels` contains
+/// (offset, name) pairs, which are printed in front of the instruction at that offset and
+/// substituted for matching jump targets.
+pub fn listing(code: &[u8], start: usize, end: usize, labels: &[(usize, String)]) -> String {
+    let code = &code[.. end];
+    let mut out = String::new();
+    let mut pos = start;
+
+    while pos < end {
+        for &(_, ref name) in labels.iter().filter(|l| l.0 == pos) {
+            writeln!(out, "{}:", name).unwrap();
+        }
+
+        let (len, text) = match decode(code, pos, labels) {
+            Some(instr) => instr,
+            None => (1, format!(".byte 0x{:02X}", code[pos]))
+        };
+
+        let bytes: Vec<String> = code[pos .. pos + len].iter().map(|b| format!("{:02X}", b)).collect();
+        writeln!(out, "{:08X}: {:<30} {}", pos, bytes.join(" "), text).unwrap();
+        pos += len;
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Prefixes {
+    seg: Option<u8>,
+    opsize: bool,
+    addr: bool,
+    lock: bool,
+    rep: Option<u8>
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Rex {
+    present: bool,
+    w: bool,
+    r: bool,
+    x: bool,
+    b: bool
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Vex {
+    xop: bool,
+    map: u8,
+    vvvv: u8,
+    l: bool,
+    pp: u8
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Base {
+    Reg(u8),
+    Rip
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MemoryRef {
+    base: Option<Base>,
+    index: Option<u8>,
+    scale: u8,
+    disp: i32
+}
+
+// the operand positions of an encoding, as indices into its format string
+#[derive(Debug, Default)]
+struct Slots {
+    m: Option<usize>,
+    r: Option<usize>,
+    v: Option<usize>,
+    i: Option<usize>
+}
+
+// decodes the instruction at `pos`, returning its length and its text
+fn decode(code: &[u8], pos: usize, labels: &[(usize, String)]) -> Option<(usize, String)> {
+    let mut p = pos;
+    let mut pre = Prefixes::default();
+    loop {
+        match *try_opt!(code.get(p)) {
+            b @ 0x26 | b @ 0x2E | b @ 0x36 | b @ 0x3E | b @ 0x64 | b @ 0x65 => pre.seg = Some(b),
+            0x66 => pre.opsize = true,
+            0x67 => pre.addr = true,
+            0xF0 => pre.lock = true,
+            b @ 0xF2 | b @ 0xF3 => pre.rep = Some(b),
+            _ => break
+        }
+        p += 1;
+    }
+
+    let mut rex = Rex::default();
+    let b = *try_opt!(code.get(p));
+    if b & 0xF0 == 0x40 {
+        rex = Rex {
+            present: true,
+            w: b & 8 != 0,
+            r: b & 4 != 0,
+            x: b & 2 != 0,
+            b: b & 1 != 0
+        };
+        p += 1;
+    }
+
+    let mut vex = None;
+    if !rex.present {
+        let b = *try_opt!(code.get(p));
+        if b == 0xC5 {
+            let b1 = *try_opt!(code.get(p + 1));
+            rex.r = b1 & 0x80 == 0;
+            vex = Some(Vex {
+                xop: false,
+                map: 1,
+                vvvv: (!b1 >> 3) & 0xF,
+                l: b1 & 4 != 0,
+                pp: b1 & 3
+            });
+            p += 2;
+        } else if b == 0xC4 || b == 0x8F {
+            let b1 = *try_opt!(code.get(p + 1));
+            // 8F is only a XOP prefix if its map select doesn't look like a modrm.reg of 0
+            if b == 0xC4 || b1 & 0x1F >= 8 {
+                let b2 = *try_opt!(code.get(p + 2));
+                rex.r = b1 & 0x80 == 0;
+                rex.x = b1 & 0x40 == 0;
+                rex.b = b1 & 0x20 == 0;
+                rex.w = b2 & 0x80 != 0;
+                vex = Some(Vex {
+                    xop: b == 0x8F,
+                    map: b1 & 0x1F,
+                    vvvv: (!b2 >> 3) & 0xF,
+                    l: b2 & 4 != 0,
+                    pp: b2 & 3
+                });
+                p += 3;
+            }
+        }
+    }
+
+    let prefixes = (pre, rex, vex);
+    let mut best: Option<((usize, usize, bool), usize, String)> = None;
+    for &(name, data) in OPS.iter() {
+        if let Some((score, end, text)) = try_match(code, p, &prefixes, name, data, labels) {
+            if best.as_ref().map_or(true, |b| score > b.0) {
+                best = Some((score, end, text));
+            }
+        }
+    }
+    best.map(|(_, end, text)| (end - pos, text))
+}
+
+fn try_match(code: &[u8], mut p: usize, prefixes: &(Prefixes, Rex, Option<Vex>),
+             name: &str, data: &Opdata, labels: &[(usize, String)]) -> Option<((usize, usize, bool), usize, String)> {
+    let (ref pre, ref rex, vex) = *prefixes;
+    let flags = data.flags;
+    let mut ops = data.ops;
+
+    // prefixes that are embedded in the VEX prefix
+    let (has_66, group1) = if let Some(vex) = vex {
+        if !flags.intersects(VEX_OP | XOP_OP) || vex.xop != flags.contains(XOP_OP) ||
+                pre.opsize || pre.lock || pre.rep.is_some() {
+            return None;
+        }
+        let (map, tail) = try_opt!(ops.split_first());
+        if *map != vex.map {
+            return None;
+        }
+        ops = tail;
+        (vex.pp == 1, match vex.pp {
+            2 => Some(0xF3),
+            3 => Some(0xF2),
+            _ => None
+        })
+    } else {
+        if flags.intersects(VEX_OP | XOP_OP) {
+            return None;
+        }
+        let group1 = match (pre.lock, pre.rep) {
+            (true, Some(_)) => return None,
+            (true, None) => Some(0xF0),
+            (false, rep) => rep
+        };
+        (pre.opsize, group1)
+    };
+    let vex_l = vex.map_or(false, |v| v.l);
+
+    // operand size
+    let op_size = if flags.contains(AUTO_SIZE) {
+        if rex.w { 8 } else if has_66 { 2 } else { 4 }
+    } else if flags.contains(AUTO_NO32) {
+        if has_66 { 2 } else { 8 }
+    } else if flags.contains(AUTO_REXW) {
+        if rex.w { 8 } else { 4 }
+    } else if flags.contains(AUTO_VEXL) {
+        if vex_l { 32 } else { 16 }
+    } else {
+        4
+    };
+
+    if has_66 != (flags.contains(WORD_SIZE) || (flags.intersects(AUTO_SIZE | AUTO_NO32) && op_size == 2)) {
+        return None;
+    }
+    if rex.w != (flags.contains(WITH_REXW) || (flags.intersects(AUTO_SIZE | AUTO_REXW) && op_size == 8)) {
+        return None;
+    }
+    if vex.is_some() && vex_l != (flags.contains(WITH_VEXL) || (flags.contains(AUTO_VEXL) && op_size == 32)) {
+        return None;
+    }
+    if flags.contains(PREF_67) && !pre.addr {
+        return None;
+    }
+    let addr32 = pre.addr && !flags.contains(PREF_67);
+
+    // mandatory prefixes vs prefixes given by the user
+    let mandatory = if flags.contains(PREF_F0) {
+        Some(0xF0)
+    } else if flags.contains(PREF_F2) {
+        Some(0xF2)
+    } else if flags.contains(PREF_F3) {
+        Some(0xF3)
+    } else {
+        None
+    };
+    let mut prefix_names = Vec::new();
+    if mandatory.is_some() {
+        if group1 != mandatory {
+            return None;
+        }
+    } else if let Some(group1) = group1 {
+        prefix_names.push(match group1 {
+            0xF0 if flags.contains(LOCK) => "lock",
+            0xF3 if flags.contains(REPE) => "repe",
+            0xF3 if flags.contains(REP) => "rep",
+            0xF2 if flags.contains(REP) => "repne",
+            _ => return None
+        });
+    }
+    if let Some(seg) = pre.seg {
+        prefix_names.push(match seg {
+            0x26 => "es",
+            0x2E => "cs",
+            0x36 => "ss",
+            0x3E => "ds",
+            0x64 => "fs",
+            _ => "gs"
+        });
+    }
+
+    // opcode
+    let short = flags.contains(SHORT_ARG);
+    let mut short_reg = 0;
+    for (i, &op) in ops.iter().enumerate() {
+        let b = *try_opt!(code.get(p));
+        if short && i == ops.len() - 1 {
+            if b & 0xF8 != op {
+                return None;
+            }
+            short_reg = b & 7;
+        } else if b != op {
+            return None;
+        }
+        p += 1;
+    }
+
+    let fmt: Vec<(u8, u8)> = FormatStringIterator::new(data.args).collect();
+    let slots = extract_slots(data, &fmt);
+
+    // modrm, sib and displacement
+    let mut modrm = None;
+    let mut mem = None;
+    if let (Some(m), false) = (slots.m, short) {
+        let b = *try_opt!(code.get(p));
+        p += 1;
+        let (mode, reg, rm) = (b >> 6, (b >> 3) & 7, b & 7);
+        if slots.r.is_none() && reg != data.reg & 7 {
+            return None;
+        }
+
+        let code_m = fmt[m].0;
+        let vsib = code_m == b'k' || code_m == b'l';
+        match code_m {
+            b'm' | b'k' | b'l' if mode == 3 => return None,
+            b'u' | b'v' | b'w' | b'm' | b'k' | b'l' => (),
+            _ if mode != 3 => return None,
+            _ => ()
+        }
+
+        if mode != 3 {
+            let mut base = None;
+            let mut index = None;
+            let mut scale = 1;
+            if rm == 4 {
+                let sib = *try_opt!(code.get(p));
+                p += 1;
+                scale = 1 << (sib >> 6);
+                let idx = (sib >> 3) & 7 | (rex.x as u8) << 3;
+                if vsib || idx != 4 {
+                    index = Some(idx);
+                }
+                if sib & 7 != 5 || mode != 0 {
+                    base = Some(Base::Reg(sib & 7 | (rex.b as u8) << 3));
+                }
+            } else if rm == 5 && mode == 0 {
+                base = Some(Base::Rip);
+            } else {
+                base = Some(Base::Reg(rm | (rex.b as u8) << 3));
+            }
+            if vsib && index.is_none() {
+                return None;
+            }
+
+            let rip = match base {
+                Some(Base::Rip) => true,
+                _ => false
+            };
+            let disp = if mode == 1 {
+                try_opt!(read_imm(code, &mut p, 1)) as i32
+            } else if mode == 2 || base.is_none() || rip {
+                try_opt!(read_imm(code, &mut p, 4)) as i32
+            } else {
+                0
+            };
+            mem = Some(MemoryRef { base: base, index: index, scale: scale, disp: disp });
+        }
+        modrm = Some((reg, rm));
+    }
+
+    // vex.vvvv has to be all ones if it isn't used
+    match (vex, slots.v) {
+        (Some(vex), None) if vex.vvvv != 0 => return None,
+        (None, Some(_)) => return None,
+        _ => ()
+    }
+
+    // immediates. a register encoded in an immediate takes the upper four bits of the first byte
+    let mut ireg = 0;
+    let mut imms = Vec::new();
+    let mut imm_args = fmt.iter().filter(|&&(c, _)| c == b'i' || c == b'o').peekable();
+    if slots.i.is_some() {
+        let b = try_opt!(read_imm(code, &mut p, 1)) as u8;
+        ireg = b >> 4;
+        if imm_args.peek().is_some() {
+            imm_args.next();
+            imms.push((b & 0xF) as i64);
+        }
+    }
+    for &(_, fsize) in imm_args {
+        let size = match fsize {
+            b'*' => if op_size == 8 { 4 } else { op_size },
+            _ => try_opt!(size_in_bytes(fsize))
+        };
+        imms.push(try_opt!(read_imm(code, &mut p, size)));
+    }
+    let end = p;
+
+    // render the operands
+    let mut args = Vec::new();
+    let mut imms = imms.into_iter();
+    for (idx, &(c, fsize)) in fmt.iter().enumerate() {
+        let size = match fsize {
+            b'*' => Some(op_size),
+            _ => size_in_bytes(fsize)
+        };
+
+        let arg = match c {
+            b'A' ... b'P' => try_opt!(legacy_reg(c - b'A', size.unwrap_or(8), rex.present)),
+            b'Q' ... b'V' => try_opt!(segment_reg(c - b'Q')),
+            b'W' => "cr8".to_string(),
+            b'X' => "st0".to_string(),
+            b'i' => format_imm(try_opt!(imms.next())),
+            b'o' => {
+                let target = end as i64 + try_opt!(imms.next());
+                match labels.iter().find(|l| l.0 as i64 == target) {
+                    Some(&(_, ref name)) => name.clone(),
+                    None => format_imm(target)
+                }
+            },
+            _ if Some(idx) == slots.m && mem.is_some() => format_memory(c, size, op_size, mem.unwrap(), addr32),
+            _ => {
+                let num = if Some(idx) == slots.m {
+                    if short {
+                        short_reg | (rex.b as u8) << 3
+                    } else {
+                        try_opt!(modrm).1 | (rex.b as u8) << 3
+                    }
+                } else if Some(idx) == slots.r {
+                    try_opt!(modrm).0 | (rex.r as u8) << 3
+                } else if Some(idx) == slots.v {
+                    try_opt!(vex).vvvv
+                } else if Some(idx) == slots.i {
+                    ireg
+                } else {
+                    return None;
+                };
+
+                match c {
+                    b'r' | b'v' => try_opt!(legacy_reg(num, size.unwrap_or(8), rex.present)),
+                    b'f' => format!("st{}", num & 7),
+                    b'x' | b'u' => format!("mmx{}", num & 7),
+                    b'y' | b'w' => format!("{}mm{}", if size == Some(32) { "y" } else { "x" }, num),
+                    b's' => try_opt!(segment_reg(num & 7)),
+                    b'c' => format!("cr{}", num),
+                    b'd' => format!("dr{}", num),
+                    _ => return None
+                }
+            }
+        };
+        args.push(arg);
+    }
+
+    let mut text = String::new();
+    for prefix in prefix_names {
+        text.push_str(prefix);
+        text.push(' ');
+    }
+    text.push_str(name);
+    if !args.is_empty() {
+        text.push(' ');
+        text.push_str(&args.join(", "));
+    }
+
+    // annotate rip relative references with the label they point at
+    if let Some(MemoryRef {base: Some(Base::Rip), disp, ..}) = mem {
+        let target = end as i64 + disp as i64;
+        if let Some(&(_, ref name)) = labels.iter().find(|l| l.0 as i64 == target) {
+            write!(text, " ; {}", name).unwrap();
+        }
+    }
+
+    // prefer the encoding with the most mandatory prefixes, the longest opcode and
+    // a fixed modrm.reg field.
+    let prefixes = [WORD_SIZE, PREF_67, PREF_F0, PREF_F2, PREF_F3, WITH_REXW, WITH_VEXL].iter()
+        .filter(|&&f| flags.contains(f))
+        .count();
+    let score = (prefixes, ops.len(), modrm.is_some() && slots.r.is_none());
+    Some((score, end, text))
+}
+
+// determines which operands are encoded in modrm.rm, modrm.reg, vex.vvvv and the
+// immediate register field. This mirrors the argument order used when assembling.
+fn extract_slots(data: &Opdata, fmt: &[(u8, u8)]) -> Slots {
+    let mut memarg = None;
+    let mut regarg = None;
+    let mut regs = Vec::new();
+
+    for (idx, &(c, _)) in fmt.iter().enumerate() {
+        match c {
+            b'm' | b'u' | b'v' | b'w' | b'k' | b'l' => {
+                memarg = Some(regs.len());
+                regs.push(idx);
+            },
+            b'f' | b'x' | b'r' | b'y' => regs.push(idx),
+            b'c' | b'd' | b's' => {
+                regarg = Some(regs.len());
+                regs.push(idx);
+            },
+            _ => ()
+        }
+    }
+
+    let mut slots = Slots::default();
+    let mut regs = regs.into_iter();
+    let len = regs.len();
+    let enc_mr = data.flags.contains(ENC_MR);
+    let enc_vm = data.flags.contains(ENC_VM);
+
+    if let Some(i) = regarg {
+        if i == 0 {
+            slots.r = regs.next();
+            slots.m = regs.next();
+        } else {
+            slots.m = regs.next();
+            slots.r = regs.next();
+        }
+    } else if len == 1 {
+        slots.m = regs.next();
+    } else if len == 2 {
+        if enc_mr || memarg == Some(0) {
+            slots.m = regs.next();
+            slots.r = regs.next();
+        } else if enc_vm {
+            slots.v = regs.next();
+            slots.m = regs.next();
+        } else {
+            slots.r = regs.next();
+            slots.m = regs.next();
+        }
+    } else if len == 3 {
+        if enc_mr || memarg == Some(1) {
+            slots.r = regs.next();
+            slots.m = regs.next();
+            slots.v = regs.next();
+        } else if enc_vm || memarg == Some(0) {
+            slots.m = regs.next();
+            slots.v = regs.next();
+            slots.r = regs.next();
+        } else {
+            slots.r = regs.next();
+            slots.v = regs.next();
+            slots.m = regs.next();
+        }
+    } else if len == 4 {
+        if enc_mr || memarg == Some(2) {
+            slots.r = regs.next();
+            slots.v = regs.next();
+            slots.m = regs.next();
+            slots.i = regs.next();
+        } else {
+            slots.r = regs.next();
+            slots.v = regs.next();
+            slots.i = regs.next();
+            slots.m = regs.next();
+        }
+    }
+    slots
+}
+
+fn size_in_bytes(fsize: u8) -> Option<usize> {
+    match fsize {
+        b'b' => Some(1),
+        b'w' => Some(2),
+        b'd' => Some(4),
+        b'q' => Some(8),
+        b'p' => Some(10),
+        b'o' => Some(16),
+        b'h' => Some(32),
+        _ => None
+    }
+}
+
+fn size_keyword(size: usize) -> &'static str {
+    match size {
+        1 => "BYTE",
+        2 => "WORD",
+        4 => "DWORD",
+        8 => "QWORD",
+        10 => "PWORD",
+        16 => "OWORD",
+        _ => "HWORD"
+    }
+}
+
+// reads a sign extended little endian immediate
+fn read_imm(code: &[u8], p: &mut usize, size: usize) -> Option<i64> {
+    if *p + size > code.len() {
+        return None;
+    }
+    let bytes = &code[*p .. *p + size];
+    *p += size;
+    let mut value = 0u64;
+    for (i, &b) in bytes.iter().enumerate() {
+        value |= (b as u64) << (i * 8);
+    }
+    let shift = 64 - size * 8;
+    Some(((value << shift) as i64) >> shift)
+}
+
+fn format_imm(value: i64) -> String {
+    if value >= 0 && value < 10 {
+        format!("{}", value)
+    } else if value < 0 {
+        format!("-0x{:X}", (value as u64).wrapping_neg())
+    } else {
+        format!("0x{:X}", value)
+    }
+}
+
+fn legacy_reg(num: u8, size: usize, rex: bool) -> Option<String> {
+    const NAMES: [&'static str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+    const BYTE_NAMES: [&'static str; 8] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil"];
+    const HIGH_BYTE_NAMES: [&'static str; 4] = ["ah", "ch", "dh", "bh"];
+
+    let num = num as usize;
+    Some(if num >= 8 {
+        match size {
+            1 => format!("r{}b", num),
+            2 => format!("r{}w", num),
+            4 => format!("r{}d", num),
+            8 => format!("r{}", num),
+            _ => return None
+        }
+    } else {
+        match size {
+            1 if !rex && num >= 4 => HIGH_BYTE_NAMES[num - 4].to_string(),
+            1 => BYTE_NAMES[num].to_string(),
+            2 => NAMES[num].to_string(),
+            4 => format!("e{}", NAMES[num]),
+            8 => format!("r{}", NAMES[num]),
+            _ => return None
+        }
+    })
+}
+
+fn segment_reg(num: u8) -> Option<String> {
+    const NAMES: [&'static str; 6] = ["es", "cs", "ss", "ds", "fs", "gs"];
+    NAMES.get(num as usize).map(|s| s.to_string())
+}
+
+fn format_memory(c: u8, size: Option<usize>, op_size: usize, mem: MemoryRef, addr32: bool) -> String {
+    let addr_size = if addr32 { 4 } else { 8 };
+    let mut text = String::new();
+
+    // vsib operands have a fixed element size, while the given size is that of the index register
+    let (size, index_size) = match c {
+        b'k' => (Some(4), size.unwrap_or(op_size)),
+        b'l' => (Some(8), size.unwrap_or(op_size)),
+        _ => (size, addr_size)
+    };
+    if let Some(size) = size {
+        text.push_str(size_keyword(size));
+        text.push(' ');
+    }
+
+    let mut parts = Vec::new();
+    match mem.base {
+        Some(Base::Reg(num)) => parts.push(legacy_reg(num, addr_size, true).unwrap()),
+        Some(Base::Rip) => parts.push("rip".to_string()),
+        None => ()
+    }
+    if let Some(index) = mem.index {
+        let index = match c {
+            b'k' | b'l' => format!("{}mm{}", if index_size == 32 { "y" } else { "x" }, index),
+            _ => legacy_reg(index, addr_size, true).unwrap()
+        };
+        parts.push(if mem.scale == 1 { index } else { format!("{} * {}", index, mem.scale) });
+    }
+
+    let mut inner = parts.join(" + ");
+    if mem.disp != 0 || inner.is_empty() {
+        if inner.is_empty() {
+            inner = format_imm(mem.disp as i64);
+        } else if mem.disp < 0 {
+            write!(inner, " - {}", format_imm(-(mem.disp as i64))).unwrap();
+        } else {
+            write!(inner, " + {}", format_imm(mem.disp as i64)).unwrap();
+        }
+    }
+    write!(text, "[{}]", inner).unwrap();
+    text
+}
@@ -0,0 +1,203 @@
+use std::io;
+use std::mem;
+
+use super::RelocKind;
+
+// This module contains a minimal writer for x86-64 ELF64 relocatable object files. The output
+// contains a single .text section (and an empty .note.GNU-stack section), a symbol table with a symbol for every exported label
+// followed by an undefined symbol for every referenced external symbol, and the relocations
+// for the external references.
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+
+const TEXT_ALIGN: usize = 16;
+
+/// An external reference to be emitted as a relocation: end offset of the reference, size, kind, symbol.
+pub type ElfReloc<'a> = (usize, u8, RelocKind, &'a str);
+
+/// Builds an ELF64 relocatable object file containing `text` as its `.text` section, which is
+/// assumed to be loaded at `text_addr`. `symbols` are the names, offsets and sizes of labels
+/// that should be exported as global symbols. As in any relocatable object, symbol values and
+/// relocation offsets are relative to the start of the section.
+pub fn write_object(text: &[u8], text_addr: u64, symbols: &[(&str, usize, usize)], relocs: &[ElfReloc]) -> io::Result<Vec<u8>> {
+    // string tables. Both start with an empty name.
+    let mut shstrtab = vec![0u8];
+    let shnames: Vec<u32> = [".text", ".symtab", ".strtab", ".rela.text", ".shstrtab", ".note.GNU-stack"].iter()
+        .map(|name| add_string(&mut shstrtab, name))
+        .collect();
+    let mut strtab = vec![0u8];
+
+    // symbol table. Index 0 is the null symbol, all our symbols are global.
+    let mut symtab = vec![0u8; 24];
+    for &(name, offset, size) in symbols {
+        let name = add_string(&mut strtab, name);
+        push_symbol(&mut symtab, name, STB_GLOBAL << 4 | STT_FUNC, 1, offset as u64, size as u64);
+    }
+
+    let mut externs: Vec<&str> = Vec::new();
+    let mut rela = Vec::new();
+    for &(end, size, kind, name) in relocs {
+        let index = match externs.iter().position(|&e| e == name) {
+            Some(index) => index,
+            None => {
+                let strindex = add_string(&mut strtab, name);
+                push_symbol(&mut symtab, strindex, STB_GLOBAL << 4 | STT_NOTYPE, 0, 0, 0);
+                externs.push(name);
+                externs.len() - 1
+            }
+        };
+        let symbol = (1 + symbols.len() + index) as u64;
+
+        // relative references are relative to the end of the reference, while the
+        // ELF ones are relative to its start.
+        let (rtype, addend) = match (kind, size) {
+            (RelocKind::Relative, 4) => (R_X86_64_PC32, -4),
+            (RelocKind::Relative, 8) => (R_X86_64_PC64, -8),
+            (RelocKind::Call, 4)     => (R_X86_64_PLT32, -4),
+            (RelocKind::Absolute, 4) => (R_X86_64_32S, 0),
+            (RelocKind::Absolute, 8) => (R_X86_64_64, 0),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Cannot encode a {:?} reference of {} bytes to '{}' in an ELF object", kind, size, name)))
+        };
+
+        push_u64(&mut rela, (end - size as usize) as u64);
+        push_u64(&mut rela, symbol << 32 | rtype as u64);
+        push_u64(&mut rela, addend as i64 as u64);
+    }
+
+    // file layout: header, .text, .symtab, .strtab, .rela.text, .shstrtab, section headers
+    let text_offset = 64;
+    let symtab_offset = align(text_offset + text.len(), 8);
+    let strtab_offset = symtab_offset + symtab.len();
+    let rela_offset = align(strtab_offset + strtab.len(), 8);
+    let shstrtab_offset = rela_offset + rela.len();
+    let shoff = align(shstrtab_offset + shstrtab.len(), 8);
+
+    let mut obj = Vec::with_capacity(shoff + 7 * 64);
+
+    // ELF header
+    obj.extend(b"\x7FELF");
+    obj.extend(&[2, 1, 1, 0]); // 64-bit, little endian, version 1, System V ABI
+    obj.extend(&[0; 8]);
+    push_u16(&mut obj, 1);  // ET_REL
+    push_u16(&mut obj, 62); // EM_X86_64
+    push_u32(&mut obj, 1);  // EV_CURRENT
+    push_u64(&mut obj, 0);  // entry
+    push_u64(&mut obj, 0);  // program header offset
+    push_u64(&mut obj, shoff as u64);
+    push_u32(&mut obj, 0);  // flags
+    push_u16(&mut obj, 64); // header size
+    push_u16(&mut obj, 0);  // program header entry size
+    push_u16(&mut obj, 0);  // program header count
+    push_u16(&mut obj, 64); // section header entry size
+    push_u16(&mut obj, 7);  // section header count
+    push_u16(&mut obj, 5);  // index of .shstrtab
+
+    obj.extend(text);
+    obj.resize(symtab_offset, 0);
+    obj.extend(&symtab);
+    obj.extend(&strtab);
+    obj.resize(rela_offset, 0);
+    obj.extend(&rela);
+    obj.extend(&shstrtab);
+    obj.resize(shoff, 0);
+
+    // section headers
+    let text_align = if text_addr & (TEXT_ALIGN as u64 - 1) == 0 {TEXT_ALIGN} else {1};
+    obj.extend(&[0; 64]);
+    let sections = [
+        Section { name: shnames[0], stype: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR, addr: text_addr,
+                  offset: text_offset, size: text.len(), link: 0, info: 0, align: text_align, entsize: 0 },
+        Section { name: shnames[1], stype: SHT_SYMTAB, flags: 0, addr: 0,
+                  offset: symtab_offset, size: symtab.len(), link: 3, info: 1, align: 8, entsize: 24 },
+        Section { name: shnames[2], stype: SHT_STRTAB, flags: 0, addr: 0,
+                  offset: strtab_offset, size: strtab.len(), link: 0, info: 0, align: 1, entsize: 0 },
+        Section { name: shnames[3], stype: SHT_RELA, flags: SHF_INFO_LINK, addr: 0,
+                  offset: rela_offset, size: rela.len(), link: 2, info: 1, align: 8, entsize: 24 },
+        Section { name: shnames[4], stype: SHT_STRTAB, flags: 0, addr: 0,
+                  offset: shstrtab_offset, size: shstrtab.len(), link: 0, info: 0, align: 1, entsize: 0 },
+        // marks the object as not requiring an executable stack
+        Section { name: shnames[5], stype: SHT_PROGBITS, flags: 0, addr: 0,
+                  offset: shoff, size: 0, link: 0, info: 0, align: 1, entsize: 0 },
+    ];
+    for section in &sections {
+        push_section(&mut obj, section);
+    }
+
+    Ok(obj)
+}
+
+fn align(offset: usize, alignment: usize) -> usize {
+    (offset + alignment - 1) & !(alignment - 1)
+}
+
+fn add_string(table: &mut Vec<u8>, name: &str) -> u32 {
+    let index = table.len() as u32;
+    table.extend(name.as_bytes());
+    table.push(0);
+    index
+}
+
+fn push_symbol(table: &mut Vec<u8>, name: u32, info: u8, section: u16, value: u64, size: u64) {
+    push_u32(table, name);
+    table.push(info);
+    table.push(0); // default visibility
+    push_u16(table, section);
+    push_u64(table, value);
+    push_u64(table, size);
+}
+
+struct Section {
+    name: u32,
+    stype: u32,
+    flags: u64,
+    addr: u64,
+    offset: usize,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: usize,
+    entsize: u64
+}
+
+fn push_section(obj: &mut Vec<u8>, section: &Section) {
+    push_u32(obj, section.name);
+    push_u32(obj, section.stype);
+    push_u64(obj, section.flags);
+    push_u64(obj, section.addr);
+    push_u64(obj, section.offset as u64);
+    push_u64(obj, section.size as u64);
+    push_u32(obj, section.link);
+    push_u32(obj, section.info);
+    push_u64(obj, section.align as u64);
+    push_u64(obj, section.entsize);
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 2]>(value.to_le()) });
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 4]>(value.to_le()) });
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 8]>(value.to_le()) });
+}
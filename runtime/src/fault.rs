@@ -0,0 +1,254 @@
+use std::cmp;
+use std::fmt::{self, Write};
+use std::io;
+use std::mem;
+use std::ptr;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, Ordering};
+
+use libc;
+
+// Reporting of faults in generated code. When installed, the handler looks up the faulting
+// instruction pointer in the code regions registered on commit and describes the location
+// on stderr before handing the signal to whatever handler was installed before it.
+
+const SIGNALS: [libc::c_int; 2] = [libc::SIGSEGV, libc::SIGILL];
+
+static INSTALLED: AtomicBool = ATOMIC_BOOL_INIT;
+static NEXT_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+static mut PREVIOUS: [Option<libc::sigaction>; 2] = [None, None];
+
+// The handler can't take any locks, so the registered regions are kept in an immutable
+// Vec<Arc<Region>> that is replaced as a whole on every change. This holds its address, or 0.
+static SNAPSHOT: AtomicUsize = ATOMIC_USIZE_INIT;
+// the amount of handlers that might be reading a snapshot. Replaced snapshots are only freed
+// once this drops to 0.
+static READERS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+lazy_static! {
+    // serializes the replacement of snapshots
+    static ref UPDATE: Mutex<()> = Mutex::new(());
+}
+
+type Handler = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+struct Region {
+    id: usize,
+    // address of the start of the executable buffer
+    base: usize,
+    // the range of offsets in the buffer this region covers
+    start: usize,
+    end: usize,
+    // length of the executable buffer, which limits the bytes that can be shown
+    len: usize,
+    // label offset -> name of the labels in this region, ordered by offset
+    labels: Vec<(usize, String)>
+}
+
+/// A region of committed code known to the fault handler. It is forgotten when dropped.
+pub struct FaultRegion {
+    id: usize
+}
+
+impl FaultRegion {
+    /// Registers the code in `start..end` of the executable buffer at `base`, which is
+    /// `len` bytes long, together with the labels defined in it.
+    pub fn new(base: usize, start: usize, end: usize, len: usize, labels: Vec<(usize, String)>) -> FaultRegion {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let region = Arc::new(Region {
+            id: id,
+            base: base,
+            start: start,
+            end: end,
+            len: len,
+            labels: labels
+        });
+        update(|regions| regions.push(region));
+        FaultRegion {
+            id: id
+        }
+    }
+}
+
+impl Drop for FaultRegion {
+    fn drop(&mut self) {
+        let id = self.id;
+        update(|regions| regions.retain(|r| r.id != id));
+    }
+}
+
+// replaces the current snapshot with a changed copy of it.
+fn update<F: FnOnce(&mut Vec<Arc<Region>>)>(f: F) {
+    let _guard = UPDATE.lock().unwrap();
+
+    let old = SNAPSHOT.load(Ordering::SeqCst) as *mut Vec<Arc<Region>>;
+    let mut regions = if old.is_null() {
+        Vec::new()
+    } else {
+        unsafe { (*old).clone() }
+    };
+    f(&mut regions);
+    SNAPSHOT.store(Box::into_raw(Box::new(regions)) as usize, Ordering::SeqCst);
+
+    // a handler that starts reading after this sees the new snapshot, so once this
+    // count drops to 0 nothing can be reading the old one anymore.
+    while READERS.load(Ordering::SeqCst) != 0 {
+        thread::yield_now();
+    }
+    if !old.is_null() {
+        unsafe {
+            drop(Box::from_raw(old));
+        }
+    }
+}
+
+/// Returns if the fault handler has been installed.
+pub fn installed() -> bool {
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+/// Installs the fault handler for SIGSEGV and SIGILL, if it wasn't already installed.
+pub fn install() -> io::Result<()> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
+    for (i, &signal) in SIGNALS.iter().enumerate() {
+        unsafe {
+            // the previous handler has to be known before ours can run
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(signal, ptr::null(), &mut previous) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            PREVIOUS[i] = Some(previous);
+
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handler as Handler as libc::sighandler_t;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handler(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    let index = if signal == libc::SIGSEGV { 0 } else { 1 };
+
+    if let Some(pc) = unsafe { fault_address(context) } {
+        READERS.fetch_add(1, Ordering::SeqCst);
+        let regions = SNAPSHOT.load(Ordering::SeqCst) as *const Vec<Arc<Region>>;
+        if !regions.is_null() {
+            let regions = unsafe { &*regions };
+            if let Some(region) = regions.iter().find(|r| pc >= r.base + r.start && pc < r.base + r.end) {
+                let mut out = StackWriter::new();
+                let _ = report(&mut out, signal, pc, region, regions);
+                unsafe {
+                    libc::write(2, out.buf.as_ptr() as *const libc::c_void, out.len);
+                }
+            }
+        }
+        // the previous handler might never return
+        READERS.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    // chain to the previous handler
+    unsafe {
+        let previous = match PREVIOUS[index] {
+            Some(previous) => previous,
+            None => return
+        };
+        if previous.sa_sigaction == libc::SIG_DFL || previous.sa_sigaction == libc::SIG_IGN {
+            // restore it. Returning executes the faulting instruction again, which now
+            // results in the default action.
+            libc::sigaction(signal, &previous, ptr::null_mut());
+        } else if previous.sa_flags & libc::SA_SIGINFO != 0 {
+            let f: Handler = mem::transmute(previous.sa_sigaction);
+            f(signal, info, context);
+        } else {
+            let f: extern "C" fn(libc::c_int) = mem::transmute(previous.sa_sigaction);
+            f(signal);
+        }
+    }
+}
+
+fn report<W: Write>(out: &mut W, signal: libc::c_int, pc: usize, region: &Region, regions: &[Arc<Region>]) -> fmt::Result {
+    let offset = pc - region.base;
+    let name = if signal == libc::SIGSEGV { "SIGSEGV" } else { "SIGILL" };
+    write!(out, "dynasmrt: {} in generated code at {:#x}, offset {:#x}", name, pc, offset)?;
+
+    // the closest preceding label might have been committed in an earlier region of the same buffer
+    let mut nearest: Option<&(usize, String)> = None;
+    for r in regions.iter().filter(|r| r.base == region.base) {
+        if let Some(label) = r.labels.iter().rev().find(|l| l.0 <= offset) {
+            nearest = match nearest {
+                Some(n) if n.0 >= label.0 => Some(n),
+                _ => Some(label)
+            };
+        }
+    }
+    if let Some(&(label, ref label_name)) = nearest {
+        write!(out, " ({}+{:#x})", label_name, offset - label)?;
+    }
+    writeln!(out, "")?;
+
+    // the surrounding bytes, with the faulting one in brackets
+    let from = offset.saturating_sub(16);
+    let to = cmp::min(offset + 16, region.len);
+    write!(out, "dynasmrt: {:#x}:", from)?;
+    for i in from..to {
+        let byte = unsafe { *((region.base + i) as *const u8) };
+        if i == offset {
+            write!(out, " [{:02x}]", byte)?;
+        } else {
+            write!(out, " {:02x}", byte)?;
+        }
+    }
+    writeln!(out)
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn fault_address(context: *mut libc::c_void) -> Option<usize> {
+    let context = context as *mut libc::ucontext_t;
+    Some((*context).uc_mcontext.gregs[libc::REG_RIP as usize] as usize)
+}
+
+#[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+unsafe fn fault_address(context: *mut libc::c_void) -> Option<usize> {
+    let context = context as *mut libc::ucontext_t;
+    Some((*(*context).uc_mcontext).__ss.__rip as usize)
+}
+
+#[cfg(not(any(all(target_os = "linux", target_arch = "x86_64"), all(target_os = "macos", target_arch = "x86_64"))))]
+unsafe fn fault_address(_context: *mut libc::c_void) -> Option<usize> {
+    None
+}
+
+// formatting into a fixed buffer, as allocating inside of a signal handler isn't safe
+struct StackWriter {
+    buf: [u8; 1024],
+    len: usize
+}
+
+impl StackWriter {
+    fn new() -> StackWriter {
+        StackWriter {
+            buf: [0; 1024],
+            len: 0
+        }
+    }
+}
+
+impl Write for StackWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        let n = cmp::min(bytes.len(), self.buf.len() - self.len);
+        self.buf[self.len .. self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
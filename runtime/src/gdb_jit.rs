@@ -0,0 +1,120 @@
+use std::ptr;
+use std::sync::Mutex;
+
+// The GDB JIT compilation interface. A debugger places a breakpoint in __jit_debug_register_code
+// and walks the linked list of in-memory object files rooted at __jit_debug_descriptor whenever
+// it is called. See "JIT Compilation Interface" in the GDB manual. As these symbols are looked
+// up by name, only a single copy of them may exist in a process.
+
+#[repr(C)]
+struct JitCodeEntry {
+    next_entry: *mut JitCodeEntry,
+    prev_entry: *mut JitCodeEntry,
+    symfile_addr: *const u8,
+    symfile_size: u64
+}
+
+#[doc(hidden)]
+#[repr(C)]
+pub struct JitDescriptor {
+    version: u32,
+    action_flag: u32,
+    relevant_entry: *mut JitCodeEntry,
+    first_entry: *mut JitCodeEntry
+}
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[doc(hidden)]
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // the debugger breaks here, so make sure this function is not optimized away.
+    unsafe {
+        ptr::read_volatile(&__jit_debug_descriptor.action_flag);
+    }
+}
+
+#[doc(hidden)]
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut()
+};
+
+lazy_static! {
+    // serializes all accesses to __jit_debug_descriptor
+    static ref LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// An in-memory object file registered with the debugger. It is unregistered when dropped.
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    // the object file the entry points to
+    _image: Vec<u8>
+}
+
+// the entry is only accessed while holding LOCK
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+
+impl Registration {
+    /// Registers `image`, an ELF object file describing code in memory, with the debugger.
+    pub fn new(image: Vec<u8>) -> Registration {
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: image.as_ptr(),
+            symfile_size: image.len() as u64
+        }));
+
+        let _guard = LOCK.lock().unwrap();
+        unsafe {
+            let first = __jit_debug_descriptor.first_entry;
+            (*entry).next_entry = first;
+            if !first.is_null() {
+                (*first).prev_entry = entry;
+            }
+            __jit_debug_descriptor.first_entry = entry;
+            __jit_debug_descriptor.relevant_entry = entry;
+            __jit_debug_descriptor.action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+            __jit_debug_descriptor.action_flag = JIT_NOACTION;
+        }
+
+        Registration {
+            entry: entry,
+            _image: image
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _guard = LOCK.lock().unwrap();
+        unsafe {
+            let entry = self.entry;
+            let prev = (*entry).prev_entry;
+            let next = (*entry).next_entry;
+            if prev.is_null() {
+                __jit_debug_descriptor.first_entry = next;
+            } else {
+                (*prev).next_entry = next;
+            }
+            if !next.is_null() {
+                (*next).prev_entry = prev;
+            }
+            __jit_debug_descriptor.relevant_entry = entry;
+            __jit_debug_descriptor.action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+            __jit_debug_descriptor.action_flag = JIT_NOACTION;
+            __jit_debug_descriptor.relevant_entry = ptr::null_mut();
+
+            drop(Box::from_raw(entry));
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::io;
+use std::mem;
+
+use super::{Assembler, ExecutableBuffer, AssemblyOffset, DynamicLabel, PatchPoint, PatchLoc, PatchKind};
+
+impl Assembler {
+    /// Allows committed code to be changed while it is possibly being executed by other threads.
+    /// In contrast to `Assembler::alter`, this method only requires read access to the internal
+    /// `ExecutableBuffer`, so it does not block on (or block) any `Executor` locks. The passed
+    /// function will be called with a `HotPatcher` as argument, which only supports a limited set of
+    /// atomic modifications. Uncommitted code cannot be modified this way.
+    ///
+    /// As the code has to stay executable while it is being patched, this is the only
+    /// exception to the rule that no memory is writeable and executable at the same time:
+    /// the executable buffer is made writeable and executable for the duration of `f`.
+    /// Systems that enforce W^X (like SELinux with execmem denied or PaX) refuse this,
+    /// in which case an error is returned and `f` is not called.
+    pub fn hotpatch<F>(&mut self, f: F) -> io::Result<()> where F: FnOnce(&mut HotPatcher) {
+        let buf = self.execbuffer.read().unwrap();
+        let ptr = buf.buffer.ptr() as *const u8;
+        let len = buf.buffer.len();
+
+        set_writable(ptr, len, true)?;
+        f(&mut HotPatcher {
+            assembler: &*self,
+            buffer: &buf
+        });
+        set_writable(ptr, len, false)
+    }
+}
+
+/// This struct allows committed code to be modified while other threads may be executing it.
+/// It is normally created using the `Assembler.hotpatch` method. Unlike an `AssemblyModifier`,
+/// it never makes the executable buffer non-executable, so the buffer is both writeable and
+/// executable while it exists. Instead, every modification is
+/// performed as a single atomic store to an aligned quadword, which is the only way
+/// cross-modifying code is guaranteed to be observed either fully or not at all by
+/// other processors. Use the `.patchable` directive to ensure that a patch site fits in
+/// such a quadword.
+///
+/// Patch points recorded with the `.patchpoint` directive already satisfy this requirement
+/// and can be changed using the `retarget` and `update` methods.
+///
+/// Note that a thread executing the patched code can still execute the old instruction
+/// for a short while after the patch has been made. Threads which have to observe the
+/// new code immediately should execute a serializing instruction (like `cpuid`) first.
+pub struct HotPatcher<'a> {
+    assembler: &'a Assembler,
+    buffer: &'a ExecutableBuffer
+}
+
+impl<'a> HotPatcher<'a> {
+    /// Atomically changes the target of the jump recorded by the given patch point to `target`.
+    pub fn retarget(&mut self, id: PatchPoint, target: AssemblyOffset) {
+        let (loc, kind) = self.patch_point(id);
+        if kind != PatchKind::Jump {
+            panic!("patch point '{}' does not refer to a jump target", id.0);
+        }
+
+        let disp = target.0 as i64 - loc.0 as i64;
+        self.write_value(loc, disp, "jump target out of range");
+    }
+
+    /// Atomically changes the target of the jump recorded by the given patch point to a dynamic label.
+    pub fn retarget_dynamic(&mut self, id: PatchPoint, label: DynamicLabel) {
+        if let Some(&Some(target)) = self.assembler.labels.dynamic_labels.get(label.0) {
+            self.retarget(id, AssemblyOffset(target));
+        } else {
+            panic!("Unkonwn dynamic label '{}'", label.0);
+        }
+    }
+
+    /// Atomically changes the target of the jump recorded by the given patch point to a global label.
+    pub fn retarget_global(&mut self, id: PatchPoint, name: &'static str) {
+        if let Some(&target) = self.assembler.labels.global_labels.get(name) {
+            self.retarget(id, AssemblyOffset(target));
+        } else {
+            panic!("Unkonwn global label '{}'", name);
+        }
+    }
+
+    /// Atomically changes the value of the immediate recorded by the given patch point to `value`.
+    pub fn update(&mut self, id: PatchPoint, value: i64) {
+        let (loc, kind) = self.patch_point(id);
+        if kind != PatchKind::Immediate {
+            panic!("patch point '{}' does not refer to an immediate", id.0);
+        }
+
+        self.write_value(loc, value, "immediate does not fit in the patch point");
+    }
+
+    /// Atomically changes the target of the 5-byte `jmp` or `call` instruction at `site` to `target`.
+    /// `site` is the offset of the start of the instruction. The instruction must not
+    /// straddle a quadword boundary, which can be ensured by emitting `.patchable 5` before it.
+    pub fn patch_jump(&mut self, site: AssemblyOffset, target: AssemblyOffset) {
+        if site.0 + 5 > self.buffer.length {
+            panic!("patch site is outside of the committed code");
+        }
+        match self.buffer[site.0] {
+            0xE8 | 0xE9 => (),
+            _ => panic!("patch site is not a 'jmp' or 'call' with a 32-bit displacement")
+        }
+
+        let disp = target.0 as isize - (site.0 + 5) as isize;
+        if disp as i32 as isize != disp {
+            panic!("jump target out of range");
+        }
+
+        let mut bytes = [0u8; 5];
+        bytes[0] = self.buffer[site.0];
+        bytes[1..].copy_from_slice(unsafe { &mem::transmute::<_, [u8; 4]>((disp as i32).to_le()) });
+        self.write_atomic(site.0, &bytes);
+    }
+
+    /// Atomically replaces the 8-byte value at `site` with `value`. This is generally used to update
+    /// the immediate of a `mov r64, imm64` instruction, in which case `site` points to the start of
+    /// the immediate. `site` must be aligned to 8 bytes, which can be ensured by emitting
+    /// `.patchable 8, 2` before the instruction.
+    pub fn patch_qword(&mut self, site: AssemblyOffset, value: u64) {
+        if site.0 + 8 > self.buffer.length {
+            panic!("patch site is outside of the committed code");
+        }
+        self.write_atomic(site.0, unsafe { &mem::transmute::<_, [u8; 8]>(value.to_le()) });
+    }
+
+    fn patch_point(&self, id: PatchPoint) -> (&'a PatchLoc, PatchKind) {
+        let (ref loc, kind) = *self.assembler.patch_points[id.0].as_ref().expect("patch point was never recorded");
+        if loc.0 > self.buffer.length {
+            panic!("patch point '{}' is outside of the committed code", id.0);
+        }
+        (loc, kind)
+    }
+
+    fn write_value(&mut self, loc: &PatchLoc, value: i64, msg: &'static str) {
+        let size = loc.1 as usize;
+        let bytes = unsafe { mem::transmute::<_, [u8; 8]>(value.to_le()) };
+        // the value has to be representable as either a signed or an unsigned value of this size
+        let shift = 64 - size as u32 * 8;
+        if size < 8 && (value << shift) >> shift != value && ((value as u64) << shift) >> shift != value as u64 {
+            panic!("{}", msg);
+        }
+        self.write_atomic(loc.0 - size, &bytes[..size]);
+    }
+
+    // atomically overwrites the bytes at offset. The range must be contained in a single aligned quadword.
+    fn write_atomic(&mut self, offset: usize, bytes: &[u8]) {
+        let word = offset & !7;
+        let start = offset - word;
+        let end = start + bytes.len();
+        if end > 8 || word + 8 > self.buffer.buffer.len() {
+            panic!("patch site is not contained in a single aligned quadword");
+        }
+
+        let ptr = (self.buffer.buffer.ptr() as usize + word) as *const u8;
+
+        // the quadword is aligned so this cast is sound, and no other access through this
+        // pointer can happen while we hold the buffer. A usize is a quadword on x86-64,
+        // the only architecture the generated code can run on.
+        let atomic = unsafe { &*(ptr as *const AtomicUsize) };
+        let mut data = unsafe { mem::transmute::<_, [u8; 8]>((atomic.load(Ordering::SeqCst) as u64).to_le()) };
+        data[start..end].copy_from_slice(bytes);
+        atomic.store(u64::from_le(unsafe { mem::transmute::<_, u64>(data) }) as usize, Ordering::SeqCst);
+    }
+}
+
+// changes the protection of the pages containing the given range between RX and RWX.
+// the range stays executable during the entire process.
+#[cfg(unix)]
+fn set_writable(ptr: *const u8, len: usize, writable: bool) -> io::Result<()> {
+    use libc;
+
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = ptr as usize & !(page - 1);
+    let end = ptr as usize + len;
+
+    let prot = libc::PROT_READ | libc::PROT_EXEC | if writable {libc::PROT_WRITE} else {0};
+    if unsafe { libc::mprotect(start as *mut libc::c_void, end - start, prot) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(windows)]
+fn set_writable(ptr: *const u8, len: usize, writable: bool) -> io::Result<()> {
+    use winapi;
+    use kernel32;
+
+    let prot = if writable {winapi::PAGE_EXECUTE_READWRITE} else {winapi::PAGE_EXECUTE_READ};
+    let mut old = 0;
+    if unsafe { kernel32::VirtualProtect(ptr as winapi::LPVOID, len as winapi::SIZE_T, prot, &mut old) } != 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
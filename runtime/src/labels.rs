@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry::*;
+use std::borrow::Cow;
+use std::mem;
+
+use super::{AssemblyOffset, DynamicLabel, PatchLoc};
+
+/// The label definitions and unresolved label references of an assembler. Offsets are
+/// assembler offsets, so the owner only has to know how to write a resolved reference
+/// into its buffer.
+#[derive(Debug)]
+pub struct LabelRegistry {
+    // label name -> target loc
+    pub global_labels: HashMap<Cow<'static, str>, usize>,
+    // end of patch location -> name
+    global_relocs: Vec<(PatchLoc, &'static str)>,
+
+    // label id -> target loc
+    pub dynamic_labels: Vec<Option<usize>>,
+    // location to be resolved, loc, label id
+    dynamic_relocs: Vec<(PatchLoc, DynamicLabel)>,
+
+    // labelname -> most recent patch location
+    local_labels: HashMap<&'static str, usize>,
+    // locations to be patched once this label gets seen. name -> Vec<locs>
+    local_relocs: HashMap<&'static str, Vec<PatchLoc>>
+}
+
+impl LabelRegistry {
+    pub fn new() -> LabelRegistry {
+        LabelRegistry {
+            global_labels: HashMap::new(),
+            global_relocs: Vec::new(),
+            dynamic_labels: Vec::new(),
+            dynamic_relocs: Vec::new(),
+            local_labels: HashMap::new(),
+            local_relocs: HashMap::new()
+        }
+    }
+
+    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        let id = self.dynamic_labels.len();
+        self.dynamic_labels.push(None);
+        DynamicLabel(id)
+    }
+
+    pub fn global_label_offset(&self, name: &str) -> Option<AssemblyOffset> {
+        self.global_labels.get(name).map(|&offset| AssemblyOffset(offset))
+    }
+
+    pub fn dynamic_label_offset(&self, id: DynamicLabel) -> Option<AssemblyOffset> {
+        self.dynamic_labels.get(id.0).and_then(|&offset| offset).map(AssemblyOffset)
+    }
+
+    pub fn define_global(&mut self, name: &'static str, offset: usize) {
+        if self.global_labels.insert(Cow::Borrowed(name), offset).is_some() {
+            panic!("Duplicate global label '{}'", name);
+        }
+    }
+
+    pub fn define_dynamic(&mut self, id: DynamicLabel, offset: usize) {
+        let entry = &mut self.dynamic_labels[id.0];
+        if entry.is_some() {
+            panic!("Duplicate label '{}'", id.0);
+        }
+        *entry = Some(offset);
+    }
+
+    /// Defines a local label, returning the forward references to it that can now be resolved.
+    pub fn define_local(&mut self, name: &'static str, offset: usize) -> Option<Vec<PatchLoc>> {
+        self.local_labels.insert(name, offset);
+        self.local_relocs.remove(&name)
+    }
+
+    pub fn global_reloc(&mut self, loc: PatchLoc, name: &'static str) {
+        self.global_relocs.push((loc, name));
+    }
+
+    pub fn dynamic_reloc(&mut self, loc: PatchLoc, id: DynamicLabel) {
+        self.dynamic_relocs.push((loc, id));
+    }
+
+    pub fn forward_reloc(&mut self, loc: PatchLoc, name: &'static str) {
+        match self.local_relocs.entry(name) {
+            Occupied(mut o) => {
+                o.get_mut().push(loc);
+            },
+            Vacant(v) => {
+                v.insert(vec![loc]);
+            }
+        }
+    }
+
+    /// Returns the target of a backward reference to a local label.
+    pub fn backward_target(&self, name: &'static str) -> usize {
+        if let Some(&target) = self.local_labels.get(&name) {
+            target
+        } else {
+            panic!("Unknown local label '{}'", name);
+        }
+    }
+
+    /// Takes all references to global and dynamic labels together with their targets,
+    /// panicking on references to labels that were never defined.
+    pub fn take_relocs(&mut self) -> Vec<(PatchLoc, usize)> {
+        let mut resolved = Vec::new();
+
+        let mut relocs = Vec::new();
+        mem::swap(&mut relocs, &mut self.global_relocs);
+        for (loc, name) in relocs {
+            if let Some(&target) = self.global_labels.get(name) {
+                resolved.push((loc, target));
+            } else {
+                panic!("Unkonwn global label '{}'", name);
+            }
+        }
+
+        let mut relocs = Vec::new();
+        mem::swap(&mut relocs, &mut self.dynamic_relocs);
+        for (loc, id) in relocs {
+            if let Some(&Some(target)) = self.dynamic_labels.get(id.0) {
+                resolved.push((loc, target));
+            } else {
+                panic!("Unkonwn dynamic label '{}'", id.0);
+            }
+        }
+
+        if let Some(name) = self.local_relocs.keys().next() {
+            panic!("Unknown local label '{}'", name);
+        }
+        resolved
+    }
+}
+
+/// Writes the displacement from the end of the reference at `loc` to `target` into `buf`,
+/// which holds the bytes of the reference.
+#[inline]
+pub fn encode_reloc(buf: &mut [u8], loc: &PatchLoc, target: usize) {
+    let target = target as isize - loc.0 as isize;
+
+    unsafe { match loc.1 {
+        1 => buf.copy_from_slice(&mem::transmute::<_, [u8; 1]>( (target as i8 ).to_le() )),
+        2 => buf.copy_from_slice(&mem::transmute::<_, [u8; 2]>( (target as i16).to_le() )),
+        4 => buf.copy_from_slice(&mem::transmute::<_, [u8; 4]>( (target as i32).to_le() )),
+        8 => buf.copy_from_slice(&mem::transmute::<_, [u8; 8]>( (target as i64).to_le() )),
+        _ => panic!("invalid patch size")
+    } }
+}
@@ -1,16 +1,51 @@
 extern crate memmap;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(windows)]
+extern crate winapi;
+#[cfg(windows)]
+extern crate kernel32;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(feature = "disasm")]
+extern crate dynasm_x64data as x64data;
+
+mod labels;
+mod hotpatch;
+mod vec_assembler;
+mod cache;
+mod elf;
+#[cfg(feature = "gdb-jit")]
+mod gdb_jit;
+#[cfg(target_os = "linux")]
+mod perf;
+mod unwind;
+#[cfg(unix)]
+mod fault;
+#[cfg(feature = "disasm")]
+mod disasm;
 
 use std::collections::HashMap;
-use std::collections::hash_map::Entry::*;
+use std::borrow::Cow;
+use std::any::Any;
+use std::fmt;
+use std::io;
 use std::ops::Deref;
 use std::iter::Extend;
 use std::mem;
 use std::cmp;
 use std::ops::DerefMut;
+#[cfg(any(unix, feature = "disasm"))]
+use std::ops::Range;
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 
 use memmap::{Mmap, Protection};
 
+pub use hotpatch::HotPatcher;
+pub use vec_assembler::VecAssembler;
+
+use labels::LabelRegistry;
+
 /// This macro takes a *const pointer from the source operand, and then casts it to the desired return type.
 /// this allows it to be used as an easy shorthand for passing pointers as dynasm immediate arguments.
 #[macro_export]
@@ -24,25 +59,104 @@ macro_rules! MutPointer {
     ($e:expr) => {$e as *mut _ as _};
 }
 
+/// Installs handlers for SIGSEGV and SIGILL that report faults inside of generated code.
+/// When the faulting instruction lies in code committed after this call, its offset, the
+/// nearest preceding global or dynamic label and the surrounding bytes are written to stderr.
+/// The signal is then passed on to the handler that was installed before.
+#[cfg(unix)]
+pub fn install_fault_handler() -> io::Result<()> {
+    fault::install()
+}
+
 /// A struct representing an offset into the assembling buffer of a `DynasmLabelApi` struct.
 /// The wrapped `usize` is the offset from the start of the assembling buffer in bytes.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct AssemblyOffset(pub usize);
 
+/// The location in the source code of an assembled instruction, as recorded when
+/// source mapping is enabled using the `.source_map` directive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceLocation {
+    pub file: &'static str,
+    pub line: u32,
+    pub column: u32,
+    pub mnemonic: &'static str
+}
+
 /// A dynamic label
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct DynamicLabel(usize);
 
-#[derive(Debug)]
+impl DynamicLabel {
+    /// Returns the identifier of this label within its assembler.
+    pub fn id(&self) -> usize {
+        self.0
+    }
+
+    /// Recreates a dynamic label from an identifier returned by `DynamicLabel::id`. This is
+    /// used to refer to dynamic labels in an assembler created by `Assembler::load`.
+    pub fn from_raw(id: usize) -> DynamicLabel {
+        DynamicLabel(id)
+    }
+}
+
+/// A patch point, identifying an immediate or jump target in committed code that can be hot patched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PatchPoint(usize);
+
+impl PatchPoint {
+    /// Returns the identifier of this patch point within its assembler.
+    pub fn id(&self) -> usize {
+        self.0
+    }
+
+    /// Recreates a patch point from an identifier returned by `PatchPoint::id`. This is
+    /// used to refer to patch points in an assembler created by `Assembler::load`.
+    pub fn from_raw(id: usize) -> PatchPoint {
+        PatchPoint(id)
+    }
+}
+
+/// The way a reference to an external symbol is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RelocKind {
+    /// The displacement from the end of the reference to the symbol.
+    Relative,
+    /// The absolute address of the symbol.
+    Absolute,
+    /// The displacement from the end of the reference to the symbol, used as the target
+    /// of a `call` or `jmp`. This is resolved like `Relative`, but tells a linker that the
+    /// reference may go through a procedure linkage table.
+    Call
+}
+
+#[derive(Debug, Clone)]
 struct PatchLoc(usize, u8);
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatchKind {
+    Jump,
+    Immediate
+}
+
 /// A structure holding a buffer of executable memory
 #[derive(Debug)]
 pub struct ExecutableBuffer {
     // length of the buffer that has actually been written to
     length: usize,
     // backing buffer
-    buffer: Mmap
+    buffer: Mmap,
+    // debugging information about the code in this buffer which is registered for as long as it lives
+    #[allow(dead_code)]
+    registrations: Registrations
+}
+
+struct Registrations(Vec<Box<Any + Send + Sync>>);
+
+impl fmt::Debug for Registrations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Registrations({})", self.0.len())
+    }
 }
 
 /// A structure wrapping some executable memory. It dereferences into a &[u8] slice.
@@ -59,6 +173,13 @@ impl ExecutableBuffer {
         &self[offset.0] as *const u8
     }
 
+    /// Returns a listing of the instructions in `range`, which is decoded using the same
+    /// instruction tables that the `dynasm!` macro uses to assemble them.
+    #[cfg(feature = "disasm")]
+    pub fn disassemble(&self, range: Range<usize>) -> String {
+        disasm::listing(self, range.start, range.end, &[])
+    }
+
     fn as_mut_slice(&mut self) -> &mut [u8] {
         unsafe {&mut self.buffer.as_mut_slice()[..self.length] }
     }
@@ -124,6 +245,63 @@ pub trait DynasmApi<'a> : Extend<u8> + Extend<&'a u8> {
             mem::transmute::<_, [u8; 8]>(value.to_le())
         }.iter().cloned());
     }
+    /// Push an unsigned octaword into the assembling target
+    #[inline]
+    fn push_u128(&mut self, value: u128) {
+        self.extend(unsafe {
+            mem::transmute::<_, [u8; 16]>(value.to_le())
+        }.iter().cloned());
+    }
+    /// Push an unsigned hexaword into the assembling target. The first element holds the
+    /// least significant half.
+    #[inline]
+    fn push_u256(&mut self, value: [u128; 2]) {
+        self.push_u128(value[0]);
+        self.push_u128(value[1]);
+    }
+    /// Push a single precision float into the assembling target
+    #[inline]
+    fn push_f32(&mut self, value: f32) {
+        self.push_i32(unsafe { mem::transmute(value) });
+    }
+    /// Push a double precision float into the assembling target
+    #[inline]
+    fn push_f64(&mut self, value: f64) {
+        self.push_i64(unsafe { mem::transmute(value) });
+    }
+    /// Push a double precision float into the assembling target, converted to the 80-bit
+    /// extended precision format used by the x87 FPU. This conversion is exact, but as the
+    /// value is an `f64`, the result never has more than double precision. `.tfloat` values
+    /// are therefore only as precise as `.double` ones, they merely use the x87 format.
+    #[inline]
+    fn push_f80(&mut self, value: f64) {
+        let bits: u64 = unsafe { mem::transmute(value) };
+        let sign = (bits >> 63) as u16;
+        let exponent = ((bits >> 52) & 0x7FF) as u16;
+        let fraction = bits & 0xF_FFFF_FFFF_FFFF;
+
+        // the extended format stores the integer bit of the significand explicitly
+        let (exponent, significand) = match exponent {
+            0 if fraction == 0 => (0, 0),
+            0 => {
+                // denormals are normal numbers in the extended format
+                let shift = fraction.leading_zeros() as u16;
+                (15372 - shift, fraction << shift)
+            },
+            0x7FF => (0x7FFF, 1 << 63 | fraction << 11),
+            _ => (exponent + (16383 - 1023), 1 << 63 | fraction << 11)
+        };
+
+        self.push_i64(significand as i64);
+        self.push_i16((sign << 15 | exponent) as i16);
+    }
+    /// This function is called at the start of every instruction when source mapping is
+    /// enabled, with the location of the instruction in the source code.
+    #[inline]
+    fn source_location(&mut self, _file: &'static str, _line: u32, _column: u32, _mnemonic: &'static str) { }
+    /// This function is called at the end of every instruction when source mapping is enabled.
+    #[inline]
+    fn source_location_end(&mut self) { }
     /// This function is called in when a runtime error has to be generated. It panics.
     #[inline]
     fn runtime_error(&self, msg: &'static str) -> ! {
@@ -135,6 +313,17 @@ pub trait DynasmApi<'a> : Extend<u8> + Extend<&'a u8> {
 pub trait DynasmLabelApi<'a> : DynasmApi<'a> {
     /// Push nops until the assembling target end is aligned to the given alignment
     fn align(&mut self, alignment: usize);
+    /// Push nops until the `size` bytes starting `offset` bytes after the assembling target end
+    /// fit in a single aligned quadword, so they can later be overwritten atomically.
+    #[inline]
+    fn align_patchable(&mut self, size: usize, offset: usize) {
+        if size > 8 {
+            panic!("patchable sites can be at most 8 bytes large");
+        }
+        while (self.offset().0 + offset) % 8 + size > 8 {
+            self.push(0x90);
+        }
+    }
     /// Record the definition of a local label
     fn local_label(  &mut self, name: &'static str);
     /// Record the definition of a global label
@@ -150,12 +339,45 @@ pub trait DynasmLabelApi<'a> : DynasmApi<'a> {
     fn global_reloc(  &mut self, name: &'static str, size: u8);
     /// Record a relocation spot for a reference to a dynamic label
     fn dynamic_reloc( &mut self, id: DynamicLabel,   size: u8);
+
+    /// Record the location of a jump target that can be retargeted later
+    fn jump_patch_point(     &mut self, id: PatchPoint, size: u8);
+    /// Record the location of an immediate that can be updated later
+    fn immediate_patch_point(&mut self, id: PatchPoint, size: u8);
+
+    // Call frame information. Registers are identified by their DWARF register numbers.
+    // Implementations that do not generate unwind information ignore these.
+
+    /// Start describing the call frames of a function starting at the current offset
+    #[inline]
+    fn cfi_start_proc(&mut self) { }
+    /// Finish describing the call frames of the current function, which ends at the current offset
+    #[inline]
+    fn cfi_end_proc(&mut self) { }
+    /// From the current offset, the CFA is `offset` bytes above the value of register `reg`
+    #[inline]
+    fn cfi_def_cfa(&mut self, _reg: u8, _offset: i32) { }
+    /// From the current offset, the CFA is `offset` bytes above the current CFA register
+    #[inline]
+    fn cfi_def_cfa_offset(&mut self, _offset: i32) { }
+    /// From the current offset, the CFA is computed from register `reg` with the current offset
+    #[inline]
+    fn cfi_def_cfa_register(&mut self, _reg: u8) { }
+    /// From the current offset, the previous value of register `reg` is saved at CFA + `offset`
+    #[inline]
+    fn cfi_offset(&mut self, _reg: u8, _offset: i32) { }
+    /// Push the current call frame state on a stack
+    #[inline]
+    fn cfi_remember_state(&mut self) { }
+    /// Pop the current call frame state from the stack
+    #[inline]
+    fn cfi_restore_state(&mut self) { }
 }
 
 /// This struct is an implementation of a dynasm runtime. It supports incremental
 /// compilation as well as multithreaded execution with simultaneous compilation.
 /// Its implementation ensures that no memory is writeable and executable at the
-/// same time.
+/// same time, except while committed code is being modified by `Assembler::hotpatch`.
 #[derive(Debug)]
 pub struct Assembler {
     // buffer where the end result is copied into
@@ -168,20 +390,29 @@ pub struct Assembler {
     // instruction buffer while building the assembly
     ops: Vec<u8>,
 
-    // label name -> target loc
-    global_labels: HashMap<&'static str, usize>,
-    // end of patch location -> name
-    global_relocs: Vec<(PatchLoc, &'static str)>,
+    // label definitions and references
+    labels: LabelRegistry,
 
-    // label id -> target loc
-    dynamic_labels: Vec<Option<usize>>,
-    // location to be resolved, loc, label id
-    dynamic_relocs: Vec<(PatchLoc, DynamicLabel)>,
+    // external symbol name -> address
+    extern_symbols: HashMap<Cow<'static, str>, usize>,
+    // end of patch location, kind, name. These are kept around after they have been
+    // resolved as they have to be resolved again whenever the code is moved.
+    extern_relocs: Vec<(PatchLoc, RelocKind, Cow<'static, str>)>,
 
-    // labelname -> most recent patch location
-    local_labels: HashMap<&'static str, usize>,
-    // locations to be patched once this label gets seen. name -> Vec<locs>
-    local_relocs: HashMap<&'static str, Vec<PatchLoc>>
+    // patch point id -> end of patch location, kind
+    patch_points: Vec<Option<(PatchLoc, PatchKind)>>,
+
+    // label id -> symbol name used in debugging information
+    dynamic_label_names: HashMap<usize, Cow<'static, str>>,
+    // call frame information of the assembled functions
+    unwind: unwind::UnwindInfo,
+
+    // if committed symbols should be written to the perf map / jitdump file
+    perf_map: bool,
+    jitdump: bool,
+
+    // instruction offset -> source location, ordered by offset
+    source_map: Vec<(AssemblyOffset, AssemblyOffset, SourceLocation)>
 }
 
 impl Assembler {
@@ -191,25 +422,140 @@ impl Assembler {
         Assembler {
             execbuffer: Arc::new(RwLock::new(ExecutableBuffer {
                 length: 0,
-                buffer: Mmap::anonymous(MMAP_INIT_SIZE, Protection::ReadExecute).expect("Failed to allocate executable memory")
+                buffer: Mmap::anonymous(MMAP_INIT_SIZE, Protection::ReadExecute).expect("Failed to allocate executable memory"),
+                registrations: Registrations(Vec::new())
             })),
             asmoffset: 0,
             map_len: MMAP_INIT_SIZE,
             ops: Vec::new(),
-            global_labels: HashMap::new(),
-            dynamic_labels: Vec::new(),
-            local_labels: HashMap::new(),
-            global_relocs: Vec::new(),
-            dynamic_relocs: Vec::new(),
-            local_relocs: HashMap::new()
+            labels: LabelRegistry::new(),
+            extern_symbols: HashMap::new(),
+            extern_relocs: Vec::new(),
+            patch_points: Vec::new(),
+            dynamic_label_names: HashMap::new(),
+            unwind: unwind::UnwindInfo::new(),
+            perf_map: false,
+            jitdump: false,
+            source_map: Vec::new()
         }
     }
 
     /// Create a new dynamic label that can be referenced and defined.
     pub fn new_dynamic_label(&mut self) -> DynamicLabel {
-        let id = self.dynamic_labels.len();
-        self.dynamic_labels.push(None);
-        DynamicLabel(id)
+        self.labels.new_dynamic_label()
+    }
+
+    /// Assign a symbol name to a dynamic label. Like global labels, named dynamic labels
+    /// are described in the debugging information generated for committed code.
+    pub fn name_dynamic_label<S: Into<Cow<'static, str>>>(&mut self, id: DynamicLabel, name: S) {
+        self.dynamic_label_names.insert(id.0, name.into());
+    }
+
+    /// Start writing all symbols in code committed by this assembler to `/tmp/perf-<pid>.map`,
+    /// which allows linux `perf` to attribute samples in this code to these symbols.
+    /// Symbols are global labels and dynamic labels named with `Assembler::name_dynamic_label`,
+    /// and are assumed to extend to the next symbol.
+    #[cfg(target_os = "linux")]
+    pub fn enable_perf_map(&mut self) -> io::Result<()> {
+        perf::open_perf_map()?;
+        self.perf_map = true;
+        Ok(())
+    }
+
+    /// Start writing all symbols in code committed by this assembler, together with their code,
+    /// to the jitdump file `/tmp/jit-<pid>.dump`. When profiling with `perf record -k mono`,
+    /// `perf inject --jit` can then be used to annotate samples in this code down to the
+    /// instruction level.
+    #[cfg(target_os = "linux")]
+    pub fn enable_jitdump(&mut self) -> io::Result<()> {
+        perf::open_jitdump()?;
+        self.jitdump = true;
+        Ok(())
+    }
+
+    /// Define the address of an external symbol. References to this symbol can be made
+    /// using `Assembler::extern_reloc`.
+    pub fn define_extern(&mut self, name: &'static str, addr: *const u8) {
+        self.extern_symbols.insert(Cow::Borrowed(name), addr as usize);
+    }
+
+    /// Record a relocation spot for a reference to an external symbol, like with the
+    /// `DynasmLabelApi` reloc methods. Unlike label references, these references are resolved
+    /// again whenever the code is moved, and they are retained by `Assembler::save`.
+    pub fn extern_reloc(&mut self, name: &'static str, size: u8, kind: RelocKind) {
+        let offset = self.offset().0;
+        self.extern_relocs.push((PatchLoc(offset, size), kind, Cow::Borrowed(name)));
+    }
+
+    /// Returns the offset of a previously defined global label.
+    pub fn global_label_offset(&self, name: &str) -> Option<AssemblyOffset> {
+        self.labels.global_label_offset(name)
+    }
+
+    /// Returns the offset of a previously defined dynamic label.
+    pub fn dynamic_label_offset(&self, id: DynamicLabel) -> Option<AssemblyOffset> {
+        self.labels.dynamic_label_offset(id)
+    }
+
+    /// Returns a listing of the committed instructions in `range`, like
+    /// `ExecutableBuffer::disassemble`. Global labels (`->name`) and dynamic labels (`=>name`
+    /// if named, `=>id` otherwise) are shown at their definitions and at jump targets.
+    #[cfg(feature = "disasm")]
+    pub fn disassemble(&self, range: Range<usize>) -> String {
+        let labels = self.label_names(0 .. ::std::usize::MAX);
+        let buf = self.execbuffer.read().unwrap();
+        disasm::listing(&buf, range.start, range.end, &labels)
+    }
+
+    // returns the offsets of the defined global and dynamic labels in range, together with
+    // how they're written in dynasm syntax, ordered by offset.
+    #[cfg_attr(not(any(unix, feature = "disasm")), allow(dead_code))]
+    fn label_names(&self, range: Range<usize>) -> Vec<(usize, String)> {
+        let in_range = |offset: usize| offset >= range.start && offset < range.end;
+        let mut labels: Vec<(usize, String)> = self.labels.global_labels.iter()
+            .filter(|&(_, &offset)| in_range(offset))
+            .map(|(name, &offset)| (offset, format!("->{}", name)))
+            .chain(self.labels.dynamic_labels.iter().enumerate().filter_map(|(id, &offset)| match offset {
+                Some(offset) if in_range(offset) => Some((offset, match self.dynamic_label_names.get(&id) {
+                    Some(name) => format!("=>{}", name),
+                    None => format!("=>{}", id)
+                })),
+                _ => None
+            }))
+            .collect();
+        labels.sort();
+        labels
+    }
+
+    /// Returns the source locations of all instructions assembled with source mapping
+    /// enabled, together with the start and end offsets of the instructions, ordered by
+    /// their offset.
+    pub fn source_map(&self) -> &[(AssemblyOffset, AssemblyOffset, SourceLocation)] {
+        &self.source_map
+    }
+
+    /// Returns the source location of the instruction containing `offset`, if it was
+    /// assembled with source mapping enabled.
+    pub fn lookup_source(&self, offset: AssemblyOffset) -> Option<&SourceLocation> {
+        let index = match self.source_map.binary_search_by_key(&offset, |&(start, _, _)| start) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1
+        };
+        let (_, end, ref location) = self.source_map[index];
+        if offset < end {
+            Some(location)
+        } else {
+            None
+        }
+    }
+
+    /// Create a new patch point that can be recorded with the `.patchpoint` directive
+    /// and later be updated using `Assembler::hotpatch`.
+    pub fn new_patch_point(&mut self) -> PatchPoint {
+        let id = self.patch_points.len();
+        self.patch_points.push(None);
+        PatchPoint(id)
     }
 
     /// To allow already committed code to be altered, this method allows modification
@@ -254,44 +600,117 @@ impl Assembler {
         });
     }
 
+    fn record_patch_point(&mut self, id: PatchPoint, loc: PatchLoc, kind: PatchKind) {
+        let entry = &mut self.patch_points[id.0];
+        if entry.is_some() {
+            panic!("Duplicate patch point '{}'", id.0);
+        }
+        *entry = Some((loc, kind));
+    }
+
     #[inline]
     fn patch_loc(&mut self, loc: PatchLoc, target: usize) {
         let buf_loc = loc.0 - self.asmoffset;
-        let buf = &mut self.ops[buf_loc - loc.1 as usize .. buf_loc];
-        let target = target as isize - loc.0 as isize;
-
-        unsafe { match loc.1 {
-            1 => buf.copy_from_slice(&mem::transmute::<_, [u8; 1]>( (target as i8 ).to_le() )),
-            2 => buf.copy_from_slice(&mem::transmute::<_, [u8; 2]>( (target as i16).to_le() )),
-            4 => buf.copy_from_slice(&mem::transmute::<_, [u8; 4]>( (target as i32).to_le() )),
-            8 => buf.copy_from_slice(&mem::transmute::<_, [u8; 8]>( (target as i64).to_le() )),
-            _ => panic!("invalid patch size")
-        } }
+        labels::encode_reloc(&mut self.ops[buf_loc - loc.1 as usize .. buf_loc], &loc, target);
     }
 
     fn encode_relocs(&mut self) {
-        let mut relocs = Vec::new();
-        mem::swap(&mut relocs, &mut self.global_relocs);
-        for (loc, name) in relocs {
-            if let Some(&target) = self.global_labels.get(&name) {
-                self.patch_loc(loc, target)
-            } else {
-                panic!("Unkonwn global label '{}'", name);
+        for (loc, target) in self.labels.take_relocs() {
+            self.patch_loc(loc, target)
+        }
+
+        // external relocs are only encoded once the final location is known, but check them now.
+        for &(ref loc, _, ref name) in &self.extern_relocs {
+            if loc.0 > self.asmoffset && !self.extern_symbols.contains_key(name) {
+                panic!("Unknown external symbol '{}'", name);
             }
         }
+    }
+
+    // encode all references to external symbols located after start into buf, which is mapped at base.
+    fn encode_extern_relocs(&self, buf: &mut [u8], base: usize, start: usize) {
+        for &(ref loc, kind, ref name) in &self.extern_relocs {
+            if loc.0 <= start {
+                continue;
+            }
+
+            let target = self.extern_symbols[name];
+            let value = match kind {
+                RelocKind::Relative | RelocKind::Call => target.wrapping_sub(base + loc.0) as isize as i64,
+                RelocKind::Absolute => target as i64
+            };
+
+            let size = loc.1 as usize;
+            let shift = 64 - size as u32 * 8;
+            if size < 8 && (value << shift) >> shift != value {
+                panic!("External symbol '{}' out of range", name);
+            }
+            let bytes = unsafe { mem::transmute::<_, [u8; 8]>(value.to_le()) };
+            buf[loc.0 - size .. loc.0].copy_from_slice(&bytes[..size]);
+        }
+    }
+
+    // returns the name, offset and size of all global labels and named dynamic labels in start..end.
+    // each symbol is assumed to extend up to the next one.
+    #[cfg_attr(not(any(feature = "gdb-jit", target_os = "linux")), allow(dead_code))]
+    fn symbols(&self, start: usize, end: usize) -> Vec<(&str, usize, usize)> {
+        let mut symbols: Vec<(&str, usize, usize)> = self.labels.global_labels.iter()
+            .map(|(name, &offset)| (&**name, offset, 0))
+            .chain(self.dynamic_label_names.iter().filter_map(|(&id, name)|
+                self.labels.dynamic_labels[id].map(|offset| (&**name, offset, 0))
+            ))
+            .filter(|&(_, offset, _)| offset >= start && offset < end)
+            .collect();
+        symbols.sort_by_key(|&(name, offset, _)| (offset, name));
+
+        for i in 0..symbols.len() {
+            let next = symbols.get(i + 1).map_or(end, |s| s.1);
+            symbols[i].2 = next - symbols[i].1;
+        }
+        symbols
+    }
+
+    // registers debugging information for the newly committed code in start..end of buf.
+    #[allow(unused_variables)]
+    fn register_code(&self, buf: &mut ExecutableBuffer, start: usize, end: usize) {
+        #[cfg(feature = "gdb-jit")]
+        {
+            let addr = buf.buffer.ptr() as u64 + start as u64;
+            let symbols: Vec<_> = self.symbols(start, end).into_iter()
+                .map(|(name, offset, size)| (name, offset - start, size))
+                .collect();
+            let image = elf::write_object(&buf[start..end], addr, &symbols, &[]).expect("Failed to describe committed code");
+            buf.registrations.0.push(Box::new(gdb_jit::Registration::new(image)));
+        }
+
+        #[cfg(unix)]
+        {
+            // register the functions that were finished in this part of the code
+            let procs: Vec<_> = self.unwind.procs.iter().filter(|p| p.end > start && p.end <= end).collect();
+            if !procs.is_empty() {
+                let (eh_frame, fdes) = unwind::build_eh_frame(buf.buffer.ptr() as usize, &procs);
+                buf.registrations.0.push(Box::new(unwind::FrameRegistration::new(eh_frame, fdes)));
+            }
 
-        let mut relocs = Vec::new();
-        mem::swap(&mut relocs, &mut self.dynamic_relocs);
-        for (loc, id) in relocs {
-            if let Some(&Some(target)) = self.dynamic_labels.get(id.0) {
-                self.patch_loc(loc, target)
-            } else {
-                panic!("Unkonwn dynamic label '{}'", id.0);
+            if fault::installed() {
+                let region = fault::FaultRegion::new(buf.buffer.ptr() as usize, start, end, buf.len(), self.label_names(start .. end));
+                buf.registrations.0.push(Box::new(region));
             }
         }
 
-        if let Some(name) = self.local_relocs.keys().next() {
-            panic!("Unknown local label '{}'", name);
+        #[cfg(target_os = "linux")]
+        {
+            if self.perf_map || self.jitdump {
+                let base = buf.buffer.ptr() as usize;
+                for (name, offset, size) in self.symbols(start, end) {
+                    if self.perf_map {
+                        perf::write_perf_map(base + offset, size, name).expect("Failed to write to the perf map");
+                    }
+                    if self.jitdump {
+                        perf::write_code_load(base + offset, &buf[offset..offset + size], name).expect("Failed to write to the jitdump file");
+                    }
+                }
+            }
         }
     }
 
@@ -299,6 +718,18 @@ impl Assembler {
     /// This method requires write access to the execution buffer and therefore
     /// has to obtain a lock on the datastructure. When this method is called, all
     /// labels will be resolved, and the result can no longer be changed.
+    ///
+    /// When the `gdb-jit` feature is enabled, the committed code is also registered with
+    /// debuggers using the GDB JIT compilation interface, with symbols for all global labels
+    /// and all dynamic labels named with `Assembler::name_dynamic_label`. This registration
+    /// lasts for as long as the code stays in the executable buffer.
+    ///
+    /// On unix platforms, the call frame information of all functions described using the
+    /// `.cfi_startproc` and `.cfi_endproc` directives is registered with the unwinder
+    /// using `__register_frame`, which allows panics and backtraces to pass through them.
+    ///
+    /// If `install_fault_handler` was called, the committed code is also made known to
+    /// the fault handler.
     pub fn commit(&mut self) {
         // This is where the part overridden by the current assembling buffer starts.
         // This is guaranteed to be in the actual backing buffer.
@@ -329,13 +760,18 @@ impl Assembler {
                 new_buf.as_mut_slice()[same].copy_from_slice(&self.execbuffer.read().unwrap().buffer.as_slice()[same]);
                 new_buf.as_mut_slice()[changed].copy_from_slice(&self.ops);
             }
+            // the code moved, so all references to external symbols have to be encoded again.
+            let base = new_buf.ptr() as usize;
+            self.encode_extern_relocs(unsafe { new_buf.as_mut_slice() }, base, 0);
             new_buf.set_protection(Protection::ReadExecute).expect("Failed to change memory protection mode");
 
             // swap the buffers and the initialized length
             let mut data = ExecutableBuffer {
                 length: buf_end,
-                buffer: new_buf
+                buffer: new_buf,
+                registrations: Registrations(Vec::new())
             };
+            self.register_code(&mut data, 0, buf_end);
             mem::swap(&mut data, &mut self.execbuffer.write().unwrap());
             // and the old buffer is dropped.
         } else {
@@ -345,11 +781,14 @@ impl Assembler {
             unsafe {
                 data.buffer.as_mut_slice()[changed].copy_from_slice(&self.ops);
             }
+            let base = data.buffer.ptr() as usize;
+            self.encode_extern_relocs(unsafe { data.buffer.as_mut_slice() }, base, buf_start);
             data.buffer.set_protection(Protection::ReadExecute).expect("Failed to change memory protection mode");
             // update the length of the initialized part of the buffer, if this commit adds length
             if buf_end > data.length {
                 data.length = buf_end;
             }
+            self.register_code(&mut data, buf_start, buf_end);
         }
         // empty the assembling buffer and update the assembling offset
         self.ops.clear();
@@ -391,6 +830,23 @@ impl<'a> DynasmApi<'a> for Assembler {
     fn push(&mut self, value: u8) {
         self.ops.push(value);
     }
+
+    fn source_location(&mut self, file: &'static str, line: u32, column: u32, mnemonic: &'static str) {
+        let offset = self.offset();
+        self.source_map.push((offset, offset, SourceLocation {
+            file: file,
+            line: line,
+            column: column,
+            mnemonic: mnemonic
+        }));
+    }
+
+    fn source_location_end(&mut self) {
+        let offset = self.offset();
+        if let Some(entry) = self.source_map.last_mut() {
+            entry.1 = offset;
+        }
+    }
 }
 
 impl<'a> DynasmLabelApi<'a> for Assembler {
@@ -407,65 +863,108 @@ impl<'a> DynasmLabelApi<'a> for Assembler {
     #[inline]
     fn global_label(&mut self, name: &'static str) {
         let offset = self.offset().0;
-        if let Some(name) = self.global_labels.insert(name, offset) {
-            panic!("Duplicate global label '{}'", name);
-        }
+        self.labels.define_global(name, offset);
     }
 
     #[inline]
     fn global_reloc(&mut self, name: &'static str, size: u8) {
         let offset = self.offset().0;
-        self.global_relocs.push((PatchLoc(offset, size), name));
+        self.labels.global_reloc(PatchLoc(offset, size), name);
     }
 
     #[inline]
     fn dynamic_label(&mut self, id: DynamicLabel) {
         let offset = self.offset().0;
-        let entry = &mut self.dynamic_labels[id.0];
-        if entry.is_some() {
-            panic!("Duplicate label '{}'", id.0);
-        }
-        *entry = Some(offset);
+        self.labels.define_dynamic(id, offset);
     }
 
     #[inline]
     fn dynamic_reloc(&mut self, id: DynamicLabel, size: u8) {
         let offset = self.offset().0;
-        self.dynamic_relocs.push((PatchLoc(offset, size), id));
+        self.labels.dynamic_reloc(PatchLoc(offset, size), id);
+    }
+
+    #[inline]
+    fn jump_patch_point(&mut self, id: PatchPoint, size: u8) {
+        let offset = self.offset().0;
+        self.record_patch_point(id, PatchLoc(offset, size), PatchKind::Jump);
+    }
+
+    #[inline]
+    fn immediate_patch_point(&mut self, id: PatchPoint, size: u8) {
+        let offset = self.offset().0;
+        self.record_patch_point(id, PatchLoc(offset, size), PatchKind::Immediate);
+    }
+
+    #[inline]
+    fn cfi_start_proc(&mut self) {
+        let offset = self.offset().0;
+        self.unwind.start_proc(offset);
+    }
+
+    #[inline]
+    fn cfi_end_proc(&mut self) {
+        let offset = self.offset().0;
+        self.unwind.end_proc(offset);
+    }
+
+    #[inline]
+    fn cfi_def_cfa(&mut self, reg: u8, offset: i32) {
+        let loc = self.offset().0;
+        self.unwind.instr(loc, unwind::CfiInstr::DefCfa(reg, offset));
+    }
+
+    #[inline]
+    fn cfi_def_cfa_offset(&mut self, offset: i32) {
+        let loc = self.offset().0;
+        self.unwind.instr(loc, unwind::CfiInstr::DefCfaOffset(offset));
+    }
+
+    #[inline]
+    fn cfi_def_cfa_register(&mut self, reg: u8) {
+        let loc = self.offset().0;
+        self.unwind.instr(loc, unwind::CfiInstr::DefCfaRegister(reg));
+    }
+
+    #[inline]
+    fn cfi_offset(&mut self, reg: u8, offset: i32) {
+        let loc = self.offset().0;
+        self.unwind.instr(loc, unwind::CfiInstr::Offset(reg, offset));
+    }
+
+    #[inline]
+    fn cfi_remember_state(&mut self) {
+        let loc = self.offset().0;
+        self.unwind.instr(loc, unwind::CfiInstr::RememberState);
+    }
+
+    #[inline]
+    fn cfi_restore_state(&mut self) {
+        let loc = self.offset().0;
+        self.unwind.instr(loc, unwind::CfiInstr::RestoreState);
     }
 
     #[inline]
     fn local_label(&mut self, name: &'static str) {
         let offset = self.offset().0;
-        if let Some(relocs) = self.local_relocs.remove(&name) {
+        if let Some(relocs) = self.labels.define_local(name, offset) {
             for loc in relocs {
                 self.patch_loc(loc, offset);
             }
         }
-        self.local_labels.insert(name, offset);
     }
 
     #[inline]
     fn forward_reloc(&mut self, name: &'static str, size: u8) {
         let offset = self.offset().0;
-        match self.local_relocs.entry(name) {
-            Occupied(mut o) => {
-                o.get_mut().push(PatchLoc(offset, size));
-            },
-            Vacant(v) => {
-                v.insert(vec![PatchLoc(offset, size)]);
-            }
-        }
+        self.labels.forward_reloc(PatchLoc(offset, size), name);
     }
 
     #[inline]
     fn backward_reloc(&mut self, name: &'static str, size: u8) {
-        if let Some(&target) = self.local_labels.get(&name) {
-            let len = self.offset().0;
-            self.patch_loc(PatchLoc(len, size), target)
-        } else {
-            panic!("Unknown local label '{}'", name);
-        }
+        let target = self.labels.backward_target(name);
+        let len = self.offset().0;
+        self.patch_loc(PatchLoc(len, size), target)
     }
 }
 
@@ -522,41 +1021,12 @@ impl<'a, 'b> AssemblyModifier<'a, 'b> {
 
     #[inline]
     fn patch_loc(&mut self, loc: PatchLoc, target: usize) {
-        let buf = &mut self.buffer.as_mut_slice()[loc.0 - loc.1 as usize .. loc.0];
-        let target = target as isize - loc.0 as isize;
-
-        unsafe { match loc.1 {
-            1 => buf.copy_from_slice(&mem::transmute::<_, [u8; 1]>( (target as i8 ).to_le() )),
-            2 => buf.copy_from_slice(&mem::transmute::<_, [u8; 2]>( (target as i16).to_le() )),
-            4 => buf.copy_from_slice(&mem::transmute::<_, [u8; 4]>( (target as i32).to_le() )),
-            8 => buf.copy_from_slice(&mem::transmute::<_, [u8; 8]>( (target as i64).to_le() )),
-            _ => panic!("invalid patch size")
-        } }
+        labels::encode_reloc(&mut self.buffer.as_mut_slice()[loc.0 - loc.1 as usize .. loc.0], &loc, target);
     }
 
     fn encode_relocs(&mut self) {
-        let mut relocs = Vec::new();
-        mem::swap(&mut relocs, &mut self.assembler.global_relocs);
-        for (loc, name) in relocs {
-            if let Some(&target) = self.assembler.global_labels.get(&name) {
-                self.patch_loc(loc, target)
-            } else {
-                panic!("Unkonwn global label '{}'", name);
-            }
-        }
-
-        let mut relocs = Vec::new();
-        mem::swap(&mut relocs, &mut self.assembler.dynamic_relocs);
-        for (loc, id) in relocs {
-            if let Some(&Some(target)) = self.assembler.dynamic_labels.get(id.0) {
-                self.patch_loc(loc, target)
-            } else {
-                panic!("Unkonwn dynamic label '{}'", id.0);
-            }
-        }
-
-        if let Some(name) = self.assembler.local_relocs.keys().next() {
-            panic!("Unknown local label '{}'", name);
+        for (loc, target) in self.assembler.labels.take_relocs() {
+            self.patch_loc(loc, target)
         }
     }
 }
@@ -600,15 +1070,24 @@ impl<'a, 'b, 'c> DynasmLabelApi<'c> for AssemblyModifier<'a, 'b> {
         self.assembler.dynamic_reloc(id, size);
     }
 
+    #[inline]
+    fn jump_patch_point(&mut self, id: PatchPoint, size: u8) {
+        self.assembler.jump_patch_point(id, size);
+    }
+
+    #[inline]
+    fn immediate_patch_point(&mut self, id: PatchPoint, size: u8) {
+        self.assembler.immediate_patch_point(id, size);
+    }
+
     #[inline]
     fn local_label(&mut self, name: &'static str) {
         let offset = self.offset().0;
-        if let Some(relocs) = self.assembler.local_relocs.remove(&name) {
+        if let Some(relocs) = self.assembler.labels.define_local(name, offset) {
             for loc in relocs {
                 self.patch_loc(loc, offset);
             }
         }
-        self.assembler.local_labels.insert(name, offset);
     }
 
     #[inline]
@@ -618,12 +1097,9 @@ impl<'a, 'b, 'c> DynasmLabelApi<'c> for AssemblyModifier<'a, 'b> {
 
     #[inline]
     fn backward_reloc(&mut self, name: &'static str, size: u8) {
-        if let Some(&target) = self.assembler.local_labels.get(&name) {
-            let len = self.offset().0;
-            self.patch_loc(PatchLoc(len, size), target)
-        } else {
-            panic!("Unknown local label '{}'", name);
-        }
+        let target = self.assembler.labels.backward_target(name);
+        let len = self.offset().0;
+        self.patch_loc(PatchLoc(len, size), target)
     }
 }
 
@@ -0,0 +1,133 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::mem;
+use std::ptr;
+
+use libc;
+
+// Support for the two ways linux perf can be told about JIT compiled code. The perf map is a
+// text file with a line per symbol that perf report reads directly. The jitdump file contains
+// the code itself and has to be merged into the recorded profile using `perf inject --jit`.
+// Both files are shared by all assemblers in the process.
+
+lazy_static! {
+    static ref PERF_MAP: Mutex<Option<File>> = Mutex::new(None);
+    static ref JITDUMP: Mutex<Option<JitDump>> = Mutex::new(None);
+}
+
+struct JitDump {
+    file: File,
+    // index of the next code load record
+    code_index: u64
+}
+
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const EM_X86_64: u32 = 62;
+
+/// Opens /tmp/perf-<pid>.map, if it wasn't already opened.
+pub fn open_perf_map() -> io::Result<()> {
+    let mut map = PERF_MAP.lock().unwrap();
+    if map.is_none() {
+        let path = format!("/tmp/perf-{}.map", pid());
+        *map = Some(OpenOptions::new().create(true).append(true).open(path)?);
+    }
+    Ok(())
+}
+
+/// Appends an entry to the perf map.
+pub fn write_perf_map(addr: usize, size: usize, name: &str) -> io::Result<()> {
+    if let Some(ref mut file) = *PERF_MAP.lock().unwrap() {
+        writeln!(file, "{:x} {:x} {}", addr, size, name)?;
+    }
+    Ok(())
+}
+
+/// Creates /tmp/jit-<pid>.dump and writes its header, if it wasn't already created.
+pub fn open_jitdump() -> io::Result<()> {
+    let mut dump = JITDUMP.lock().unwrap();
+    if dump.is_some() {
+        return Ok(());
+    }
+
+    let path = format!("/tmp/jit-{}.dump", pid());
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+
+    let mut header = Vec::with_capacity(40);
+    push_u32(&mut header, JITDUMP_MAGIC);
+    push_u32(&mut header, JITDUMP_VERSION);
+    push_u32(&mut header, 40); // header size
+    push_u32(&mut header, EM_X86_64);
+    push_u32(&mut header, 0);  // padding
+    push_u32(&mut header, pid());
+    push_u64(&mut header, timestamp());
+    push_u64(&mut header, 0);  // flags
+    file.write_all(&header)?;
+
+    // perf record only notices the dump file if it is mapped as executable. The mapping
+    // is intentionally leaked as it has to stay around for the lifetime of the process.
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let marker = unsafe {
+        libc::mmap(ptr::null_mut(), page, libc::PROT_READ | libc::PROT_EXEC, libc::MAP_PRIVATE, file.as_raw_fd(), 0)
+    };
+    if marker == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    *dump = Some(JitDump {
+        file: file,
+        code_index: 0
+    });
+    Ok(())
+}
+
+/// Writes a code load record for `code`, which is located at `addr`, to the jitdump file.
+pub fn write_code_load(addr: usize, code: &[u8], name: &str) -> io::Result<()> {
+    if let Some(ref mut dump) = *JITDUMP.lock().unwrap() {
+        let size = 16 + 40 + name.len() + 1 + code.len();
+        let mut record = Vec::with_capacity(size);
+        push_u32(&mut record, JIT_CODE_LOAD);
+        push_u32(&mut record, size as u32);
+        push_u64(&mut record, timestamp());
+        push_u32(&mut record, pid());
+        push_u32(&mut record, unsafe { libc::syscall(libc::SYS_gettid) } as u32);
+        push_u64(&mut record, addr as u64); // virtual address
+        push_u64(&mut record, addr as u64); // code address
+        push_u64(&mut record, code.len() as u64);
+        push_u64(&mut record, dump.code_index);
+        record.extend(name.as_bytes());
+        record.push(0);
+        record.extend(code);
+
+        dump.file.write_all(&record)?;
+        dump.code_index += 1;
+    }
+    Ok(())
+}
+
+// perf has to be told to use the same clock using `perf record -k mono`
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn pid() -> u32 {
+    unsafe { libc::getpid() as u32 }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 4]>(value.to_le()) });
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 8]>(value.to_le()) });
+}
@@ -0,0 +1,281 @@
+// Generation of DWARF call frame information in the .eh_frame format, which allows unwinders
+// to walk through frames of generated code. Every function delimited by .cfi_startproc and
+// .cfi_endproc gets a frame description entry (FDE) which all refer to a single common
+// information entry (CIE) describing the state at function entry according to the SysV ABI.
+
+use std::mem;
+
+/// A call frame instruction, recorded at an offset in the assembled code.
+#[derive(Debug, Clone)]
+pub enum CfiInstr {
+    DefCfa(u8, i32),
+    DefCfaOffset(i32),
+    DefCfaRegister(u8),
+    Offset(u8, i32),
+    RememberState,
+    RestoreState
+}
+
+/// The call frame information of a single function.
+#[derive(Debug, Clone)]
+pub struct UnwindProc {
+    pub start: usize,
+    pub end: usize,
+    instrs: Vec<(usize, CfiInstr)>
+}
+
+/// The call frame information recorded by an assembler.
+#[derive(Debug)]
+pub struct UnwindInfo {
+    // function currently being described
+    current: Option<UnwindProc>,
+    // finished functions
+    pub procs: Vec<UnwindProc>
+}
+
+impl UnwindInfo {
+    pub fn new() -> UnwindInfo {
+        UnwindInfo {
+            current: None,
+            procs: Vec::new()
+        }
+    }
+
+    pub fn start_proc(&mut self, offset: usize) {
+        if self.current.is_some() {
+            panic!("'.cfi_startproc' inside of another function");
+        }
+        self.current = Some(UnwindProc {
+            start: offset,
+            end: offset,
+            instrs: Vec::new()
+        });
+    }
+
+    pub fn end_proc(&mut self, offset: usize) {
+        let mut current = self.current.take().expect("'.cfi_endproc' without '.cfi_startproc'");
+        current.end = offset;
+        self.procs.push(current);
+    }
+
+    pub fn instr(&mut self, offset: usize, instr: CfiInstr) {
+        let current = self.current.as_mut().expect("call frame directive outside of '.cfi_startproc' and '.cfi_endproc'");
+        current.instrs.push((offset, instr));
+    }
+}
+
+// DWARF register number of the return address
+const RA_REGISTER: u8 = 16;
+// all stack slots are 8 bytes
+const DATA_ALIGN: i32 = -8;
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_OFFSET_EXTENDED_SF: u8 = 0x11;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0A;
+const DW_CFA_RESTORE_STATE: u8 = 0x0B;
+const DW_CFA_DEF_CFA: u8 = 0x0C;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0D;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0E;
+
+/// Builds the .eh_frame data for `procs`, where the code starts at address `base`. Returns
+/// the data, terminated by a zero length entry, and the offsets of the FDEs in it.
+pub fn build_eh_frame(base: usize, procs: &[&UnwindProc]) -> (Vec<u8>, Vec<usize>) {
+    let mut buf = Vec::new();
+
+    // CIE
+    let cie = start_entry(&mut buf);
+    push_u32(&mut buf, 0);           // CIE id
+    buf.push(1);                     // version
+    buf.extend(b"zR\0");             // augmentation: FDE pointer encoding is specified
+    push_uleb(&mut buf, 1);          // code alignment
+    push_sleb(&mut buf, DATA_ALIGN as i64);
+    buf.push(RA_REGISTER);
+    push_uleb(&mut buf, 1);          // augmentation data length
+    buf.push(0x00);                  // DW_EH_PE_absptr
+    // at function entry, CFA = rsp + 8 and the return address is stored at CFA - 8
+    buf.push(DW_CFA_DEF_CFA);
+    push_uleb(&mut buf, 7);
+    push_uleb(&mut buf, 8);
+    buf.push(DW_CFA_OFFSET | RA_REGISTER);
+    push_uleb(&mut buf, 1);
+    end_entry(&mut buf, cie);
+
+    // FDEs
+    let mut fdes = Vec::new();
+    for p in procs {
+        let fde = start_entry(&mut buf);
+        fdes.push(fde);
+        let cie_pointer = (buf.len() - cie) as u32;
+        push_u32(&mut buf, cie_pointer);
+        push_u64(&mut buf, (base + p.start) as u64);
+        push_u64(&mut buf, (p.end - p.start) as u64);
+        push_uleb(&mut buf, 0); // augmentation data length
+
+        let mut loc = p.start;
+        for &(offset, ref instr) in &p.instrs {
+            advance_loc(&mut buf, offset - loc);
+            loc = offset;
+
+            match *instr {
+                CfiInstr::DefCfa(reg, offset) => {
+                    buf.push(DW_CFA_DEF_CFA);
+                    push_uleb(&mut buf, reg as u64);
+                    push_uleb(&mut buf, cfa_offset(offset));
+                },
+                CfiInstr::DefCfaOffset(offset) => {
+                    buf.push(DW_CFA_DEF_CFA_OFFSET);
+                    push_uleb(&mut buf, cfa_offset(offset));
+                },
+                CfiInstr::DefCfaRegister(reg) => {
+                    buf.push(DW_CFA_DEF_CFA_REGISTER);
+                    push_uleb(&mut buf, reg as u64);
+                },
+                CfiInstr::Offset(reg, offset) => {
+                    if offset % DATA_ALIGN != 0 {
+                        panic!("register save offsets have to be a multiple of 8");
+                    }
+                    let factored = offset / DATA_ALIGN;
+                    if factored >= 0 && reg < 64 {
+                        buf.push(DW_CFA_OFFSET | reg);
+                        push_uleb(&mut buf, factored as u64);
+                    } else {
+                        buf.push(DW_CFA_OFFSET_EXTENDED_SF);
+                        push_uleb(&mut buf, reg as u64);
+                        push_sleb(&mut buf, factored as i64);
+                    }
+                },
+                CfiInstr::RememberState => buf.push(DW_CFA_REMEMBER_STATE),
+                CfiInstr::RestoreState => buf.push(DW_CFA_RESTORE_STATE)
+            }
+        }
+        end_entry(&mut buf, fde);
+    }
+
+    push_u32(&mut buf, 0);
+    (buf, fdes)
+}
+
+fn cfa_offset(offset: i32) -> u64 {
+    if offset < 0 {
+        panic!("the CFA offset cannot be negative");
+    }
+    offset as u64
+}
+
+fn advance_loc(buf: &mut Vec<u8>, delta: usize) {
+    match delta {
+        0 => (),
+        1 ... 0x3F => buf.push(DW_CFA_ADVANCE_LOC | delta as u8),
+        0x40 ... 0xFF => {
+            buf.push(DW_CFA_ADVANCE_LOC1);
+            buf.push(delta as u8);
+        },
+        0x100 ... 0xFFFF => {
+            buf.push(DW_CFA_ADVANCE_LOC2);
+            buf.extend(unsafe { &mem::transmute::<_, [u8; 2]>((delta as u16).to_le()) });
+        },
+        _ => {
+            buf.push(DW_CFA_ADVANCE_LOC4);
+            push_u32(buf, delta as u32);
+        }
+    }
+}
+
+// reserves space for the length of an entry and returns its offset
+fn start_entry(buf: &mut Vec<u8>) -> usize {
+    let start = buf.len();
+    buf.extend(&[0; 4]);
+    start
+}
+
+// pads the entry to 8 bytes with DW_CFA_nop and fills in its length
+fn end_entry(buf: &mut Vec<u8>, start: usize) {
+    while (buf.len() - start) & 7 != 0 {
+        buf.push(0);
+    }
+    let len = (buf.len() - start - 4) as u32;
+    buf[start .. start + 4].copy_from_slice(unsafe { &mem::transmute::<_, [u8; 4]>(len.to_le()) });
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 4]>(value.to_le()) });
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend(unsafe { &mem::transmute::<_, [u8; 8]>(value.to_le()) });
+}
+
+fn push_uleb(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+fn push_sleb(buf: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Call frame information registered with the unwinder. It is deregistered when dropped.
+#[cfg(unix)]
+pub struct FrameRegistration {
+    // the registered data, which has to stay alive until it is deregistered
+    _eh_frame: Vec<u8>,
+    // pointers passed to __register_frame
+    registered: Vec<usize>
+}
+
+#[cfg(unix)]
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+#[cfg(unix)]
+impl FrameRegistration {
+    /// Registers the frame information built by `build_eh_frame`.
+    pub fn new(eh_frame: Vec<u8>, fdes: Vec<usize>) -> FrameRegistration {
+        // libgcc takes an entire .eh_frame section, while the libunwind used
+        // on macOS takes single FDEs.
+        let registered: Vec<usize> = if cfg!(target_os = "macos") {
+            fdes.iter().map(|&fde| eh_frame.as_ptr() as usize + fde).collect()
+        } else {
+            vec![eh_frame.as_ptr() as usize]
+        };
+
+        for &ptr in &registered {
+            unsafe { __register_frame(ptr as *const u8) };
+        }
+
+        FrameRegistration {
+            _eh_frame: eh_frame,
+            registered: registered
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for FrameRegistration {
+    fn drop(&mut self) {
+        for &ptr in &self.registered {
+            unsafe { __deregister_frame(ptr as *const u8) };
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::iter::Extend;
+use std::io::{self, Write};
+
+use super::{DynasmApi, DynasmLabelApi, AssemblyOffset, DynamicLabel, PatchPoint, PatchLoc, RelocKind};
+use labels::{self, LabelRegistry};
+use elf;
+
+/// This struct is an implementation of a dynasm runtime that assembles into a plain `Vec<u8>`
+/// instead of executable memory. It supports all label types, which makes it useful for
+/// generating code ahead of time, for generating code for another process or for
+/// environments where executable memory cannot be allocated. As the final location of the
+/// code is not necessarily known, a base address can be specified. This address is only
+/// used for alignment. As the code is not executed in place, patch points recorded with
+/// `.patchpoint` are ignored.
+#[derive(Debug)]
+pub struct VecAssembler {
+    // the assembled code
+    ops: Vec<u8>,
+    // address the code is expected to be loaded at
+    baseaddr: usize,
+
+    // label definitions and references
+    labels: LabelRegistry,
+
+    // end of patch location, kind, name
+    extern_relocs: Vec<(PatchLoc, RelocKind, &'static str)>
+}
+
+impl VecAssembler {
+    /// Create a new `VecAssembler` instance, which assembles code to be loaded at `baseaddr`.
+    pub fn new(baseaddr: usize) -> VecAssembler {
+        VecAssembler {
+            ops: Vec::new(),
+            baseaddr: baseaddr,
+            labels: LabelRegistry::new(),
+            extern_relocs: Vec::new()
+        }
+    }
+
+    /// Create a new dynamic label that can be referenced and defined.
+    pub fn new_dynamic_label(&mut self) -> DynamicLabel {
+        self.labels.new_dynamic_label()
+    }
+
+    /// The address the assembled code is expected to be loaded at.
+    pub fn baseaddr(&self) -> usize {
+        self.baseaddr
+    }
+
+    /// Record a reference to the external symbol `name` which ends at the current offset.
+    /// As the addresses of external symbols are not known, these references are left as zeroes
+    /// in the assembled code. They are only encoded as relocations by `VecAssembler::write_elf`.
+    pub fn extern_reloc(&mut self, name: &'static str, size: u8, kind: RelocKind) {
+        let offset = self.offset().0;
+        self.extern_relocs.push((PatchLoc(offset, size), kind, name));
+    }
+
+    /// Returns the offset of a previously defined global label.
+    pub fn global_label_offset(&self, name: &str) -> Option<AssemblyOffset> {
+        self.labels.global_label_offset(name)
+    }
+
+    /// Returns the offset of a previously defined dynamic label.
+    pub fn dynamic_label_offset(&self, id: DynamicLabel) -> Option<AssemblyOffset> {
+        self.labels.dynamic_label_offset(id)
+    }
+
+    /// Resolves all references to global and dynamic labels made so far. This is done
+    /// automatically by `VecAssembler::finalize`.
+    pub fn commit(&mut self) {
+        for (loc, target) in self.labels.take_relocs() {
+            self.patch_loc(loc, target)
+        }
+    }
+
+    /// Consumes the assembler, resolving all label references, and returns the assembled code.
+    pub fn finalize(mut self) -> Vec<u8> {
+        self.commit();
+        self.ops
+    }
+
+    /// Resolves all label references and writes the assembled code to `w` as an x86-64 ELF64
+    /// relocatable object file, which can be linked using the system linker. All global labels
+    /// are exported as global symbols, as are the dynamic labels in `dynamic_symbols` under
+    /// the given names. References made using `VecAssembler::extern_reloc` are emitted as
+    /// `R_X86_64_PC32`, `R_X86_64_PLT32` or `R_X86_64_64` relocations. The base address of the
+    /// assembler is not used, as the linker decides where the `.text` section ends up.
+    pub fn write_elf<W: Write>(&mut self, w: &mut W, dynamic_symbols: &[(&str, DynamicLabel)]) -> io::Result<()> {
+        self.commit();
+
+        let mut symbols: Vec<(&str, usize, usize)> = self.labels.global_labels.iter().map(|(name, &offset)| (&**name, offset, 0)).collect();
+        symbols.sort();
+        for &(name, id) in dynamic_symbols {
+            if let Some(offset) = self.dynamic_label_offset(id) {
+                symbols.push((name, offset.0, 0));
+            } else {
+                panic!("Unkonwn dynamic label '{}'", id.0);
+            }
+        }
+
+        let relocs: Vec<elf::ElfReloc> = self.extern_relocs.iter()
+            .map(|&(ref loc, kind, name)| (loc.0, loc.1, kind, name))
+            .collect();
+
+        let obj = elf::write_object(&self.ops, 0, &symbols, &relocs)?;
+        w.write_all(&obj)
+    }
+
+    #[inline]
+    fn patch_loc(&mut self, loc: PatchLoc, target: usize) {
+        labels::encode_reloc(&mut self.ops[loc.0 - loc.1 as usize .. loc.0], &loc, target);
+    }
+}
+
+impl<'a> DynasmApi<'a> for VecAssembler {
+    #[inline]
+    fn offset(&self) -> AssemblyOffset {
+        AssemblyOffset(self.ops.len())
+    }
+
+    #[inline]
+    fn push(&mut self, value: u8) {
+        self.ops.push(value);
+    }
+}
+
+impl<'a> DynasmLabelApi<'a> for VecAssembler {
+    #[inline]
+    fn align(&mut self, alignment: usize) {
+        let offset = (self.baseaddr + self.ops.len()) % alignment;
+        if offset != 0 {
+            for _ in 0..(alignment - offset) {
+                self.push(0x90);
+            }
+        }
+    }
+
+    #[inline]
+    fn align_patchable(&mut self, size: usize, offset: usize) {
+        if size > 8 {
+            panic!("patchable sites can be at most 8 bytes large");
+        }
+        while (self.baseaddr + self.ops.len() + offset) % 8 + size > 8 {
+            self.push(0x90);
+        }
+    }
+
+    #[inline]
+    fn global_label(&mut self, name: &'static str) {
+        let offset = self.offset().0;
+        self.labels.define_global(name, offset);
+    }
+
+    #[inline]
+    fn global_reloc(&mut self, name: &'static str, size: u8) {
+        let offset = self.offset().0;
+        self.labels.global_reloc(PatchLoc(offset, size), name);
+    }
+
+    #[inline]
+    fn dynamic_label(&mut self, id: DynamicLabel) {
+        let offset = self.offset().0;
+        self.labels.define_dynamic(id, offset);
+    }
+
+    #[inline]
+    fn dynamic_reloc(&mut self, id: DynamicLabel, size: u8) {
+        let offset = self.offset().0;
+        self.labels.dynamic_reloc(PatchLoc(offset, size), id);
+    }
+
+    // patch points only make sense for code in executable memory, so they are ignored here.
+    #[inline]
+    fn jump_patch_point(&mut self, _id: PatchPoint, _size: u8) { }
+
+    #[inline]
+    fn immediate_patch_point(&mut self, _id: PatchPoint, _size: u8) { }
+
+    #[inline]
+    fn local_label(&mut self, name: &'static str) {
+        let offset = self.offset().0;
+        if let Some(relocs) = self.labels.define_local(name, offset) {
+            for loc in relocs {
+                self.patch_loc(loc, offset);
+            }
+        }
+    }
+
+    #[inline]
+    fn forward_reloc(&mut self, name: &'static str, size: u8) {
+        let offset = self.offset().0;
+        self.labels.forward_reloc(PatchLoc(offset, size), name);
+    }
+
+    #[inline]
+    fn backward_reloc(&mut self, name: &'static str, size: u8) {
+        let target = self.labels.backward_target(name);
+        let len = self.offset().0;
+        self.patch_loc(PatchLoc(len, size), target)
+    }
+}
+
+impl Extend<u8> for VecAssembler {
+    #[inline]
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=u8> {
+        self.ops.extend(iter)
+    }
+}
+
+impl<'a> Extend<&'a u8> for VecAssembler {
+    #[inline]
+    fn extend<T>(&mut self, iter: T) where T: IntoIterator<Item=&'a u8> {
+        self.extend(iter.into_iter().cloned())
+    }
+}
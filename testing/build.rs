@@ -0,0 +1,10 @@
+extern crate dynasm_build;
+
+use std::env;
+use std::path::Path;
+
+// expands the dynasm file src/prebuilt.dasm into plain rust code, which is included by main.rs
+fn main() {
+    let out = env::var("OUT_DIR").unwrap();
+    dynasm_build::preprocess("src/prebuilt.dasm", Path::new(&out).join("prebuilt.rs")).unwrap();
+}
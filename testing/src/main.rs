@@ -11,7 +11,11 @@ dynasm!(ops
 );
 
 fn main() {
+    // report crashes in the generated code
+    install_fault_handler();
+
     let mut ops = dynasmrt::Assembler::new();
+    enable_profiling(&mut ops);
     let d = 3;
     let c = 4;
 
@@ -25,6 +29,65 @@ fn main() {
         ; ret 16
         // register
         ; inc test
+        // block local aliases, shadowing and undefining
+        ; .local_alias counter, rcx
+        ; inc counter
+        ; .local_alias test, rdx
+        ; inc test
+        ; .unalias test
+        ; inc test
+        // aliases for dynamic registers and memory references
+        ; .local_alias acc, Rq(2)
+        ; .local_alias slot, QWORD [rbp - 16]
+        ; mov acc, slot
+        ; inc DWORD slot
+        // constants and structures
+        ; .equ SLOTS, 4
+        ; .struct Point { x: DWORD, y: DWORD }
+        ; .struct Frame { prev: QWORD, ret_addr: QWORD, flags: BYTE, origin: Point, slots: [QWORD; SLOTS] }
+        ; mov rax, [rbx + Frame.ret_addr]
+        ; inc [rbx + Frame.origin.y]
+        ; mov rcx, QWORD [rbx + Frame.slots + 8 * (SLOTS - 1)]
+        // assembler macros
+        ; .macro clamp_max reg, limit
+        ;     cmp reg, limit
+        ;     jle >ok
+        ;     mov reg, limit
+        ; ok:
+        ; .endmacro
+        ; clamp_max rax, 255
+        ; clamp_max Rq(2), QWORD [rsp + 8]
+        // conditional assembly
+        ; .if cfg!(target_os = "windows")
+        ;     mov rcx, rax
+        ; .else
+        ;     mov rdi, rax
+        ; .endif
+        ; .if d > c
+        ;     inc rax
+        ; .elif d == c
+        ;     dec rax
+        ; .else
+        ;     .if true
+        ;         nop
+        ;     .endif
+        ; .endif
+        // structured control flow
+        ; test rax, rax
+        ; .if_cc z
+        ;     mov rax, 1
+        ; .else
+        ;     neg rax
+        ; .endif
+        ; mov Rq(2), 10
+        ; test Rq(2), Rq(2)
+        ; .while_cc nz
+        ;     add rax, Rq(2)
+        ;     .if_cc s
+        ;         xor rax, rax
+        ;     .endif
+        ;     dec Rq(2)
+        ; .endwhile
         // memory ref
         ; inc DWORD [16]
         ; inc DWORD [test]
@@ -119,8 +182,25 @@ fn main() {
         // 4 argument instructions
         ; vfmaddss xmm0, xmm1, xmm2, xmm3
         // directives
+        ; .patchable 5
+        ; call ->b
+        ; .patchable 8, 2
+        ; mov rax, QWORD 0x1122334455667788
         ; string:
         //; .bytes "Hello world!\0".bytes()
+        // data
+        ; .align 16
+        ; sign_mask:
+        ; .oword 0x8000_0000_8000_0000_8000_0000_8000_0000
+        ; .hword [!0, 0]
+        ; .float 1.5, -0.25
+        ; .double ::std::f64::consts::PI
+        ; .tfloat 1.0 / 3.0
+        ; .ascii "Hello", b" world"
+        ; .asciz "!"
+        ; .utf16 "C:\\Windows\0"
+        ; .space 3
+        ; .fill 4, 2, 0x9066
     );
 
     // typemap support
@@ -144,6 +224,29 @@ fn main() {
         ; inc DWORD rax => Test.bar
     );
 
+    // nested and positional fields, with the size taken from the field
+    #[allow(dead_code)]
+    struct Outer {
+        flags: u8,
+        inner: Test,
+        pair: (u16, u64)
+    }
+    let mut test_outer = Outer {flags: 0, inner: Test {foo: 9, bar: 10}, pair: (11, 12)};
+    let mut test_outer = &mut test_outer;
+    dynasm!(ops
+        ; mov rax, AWORD MutPointer!(test_outer)
+        ; inc rax => Outer.flags
+        ; inc rax => Outer.inner.bar
+        ; inc rax => Outer.pair.0
+        ; mov rcx, rax => Outer.pair.1
+        // element sizes that can't be encoded as a scale
+        ; .struct Entry { key: QWORD, value: DWORD }
+        ; .typemap_scratch r11
+        ; mov ecx, rax => u32[rbx]
+        ; mov ecx, rax => struct Entry[rbx].value
+        ; inc rax => Outer[rbx].flags
+    );
+
     // dynasm in expr position
     match 1 {
         0 => (),
@@ -164,12 +267,79 @@ fn main() {
         ops.check(end);
     });
 
+    // patch points
+    let site = ops.new_patch_point();
+    let value = ops.new_patch_point();
+    dynasm!(ops
+        ; .patchpoint site
+        ; jmp >skip
+        ; skip:
+        ; .patchpoint value
+        ; mov rax, QWORD 0
+    );
+    ops.commit();
+    ops.hotpatch(|patcher| {
+        patcher.retarget_global(site, "b");
+        patcher.update(value, 0x1122334455667788);
+    }).expect("Failed to make the code writeable");
+
+    // call frame information
+    dynasm!(ops
+        ; .cfi_startproc
+        ; push rbp
+        ; .cfi_def_cfa_offset 16
+        ; .cfi_offset rbp, -16
+        ; mov rbp, rsp
+        ; .cfi_def_cfa_register rbp
+        ; .cfi_remember_state
+        ; pop rbp
+        ; .cfi_def_cfa rsp, 8
+        ; ret
+        ; .cfi_restore_state
+        ; .cfi_endproc
+    );
+
+    // source mapping
     let index = ops.offset();
     dynasm!(ops
+        ; .source_map
         ; mov eax, 10203040
         ; ret
     );
 
+    // code expanded ahead of time by dynasm-build, see build.rs
+    let prebuilt = ops.offset();
+    include!(concat!(env!("OUT_DIR"), "/prebuilt.rs"));
+
+    // assembling into a plain vector
+    let mut vecops = dynasmrt::VecAssembler::new(0x1000);
+    let vec_label = vecops.new_dynamic_label();
+    dynasm!(vecops
+        ; ->start:
+        ; jmp >end
+        ; => vec_label
+        ; end:
+        ; jmp ->start
+        ; jmp => vec_label
+    );
+    let mut object = Vec::new();
+    vecops.write_elf(&mut object, &[("vec_label", vec_label)]).unwrap();
+    println!("Generated a {} byte ELF object", object.len());
+    let vec_bytes = vecops.finalize();
+    println!("Generated {} bytes into a vector", vec_bytes.len());
+
+    if let Some(loc) = ops.lookup_source(index) {
+        println!("'{}' was assembled from {}:{}:{}", loc.mnemonic, loc.file, loc.line, loc.column);
+    }
+
+    let mut cache = Vec::new();
+    let label_id = label.id();
+    ops.save(&mut cache).unwrap();
+    let cached = dynasmrt::Assembler::load(&mut &cache[..], |_| None).unwrap();
+    println!("Reloaded {} bytes of cached code", cached.offset().0);
+    let cached_label = dynasmrt::DynamicLabel::from_raw(label_id);
+    println!("Dynamic label {} was reloaded at {:?}", label_id, cached.dynamic_label_offset(cached_label));
+
     let buf = ops.finalize().unwrap();
 
     println!("Generated assembly:");
@@ -178,6 +348,34 @@ fn main() {
     }
     println!("");
 
+    println!("Disassembly:");
+    print!("{}", buf.disassemble(index.0 .. buf.len()));
+
     let func: extern "C" fn() -> i64 = unsafe { std::mem::transmute(buf.ptr(index)) };
     println!("assembled function result: {}", func() );
+
+    let prebuilt_func: extern "C" fn() -> i32 = unsafe { std::mem::transmute(buf.ptr(prebuilt)) };
+    println!("prebuilt function result: {}", prebuilt_func() );
 }
+
+// describe the committed code to linux perf in /tmp/perf-<pid>.map and /tmp/jit-<pid>.dump
+// if DYNASM_PROFILE is set in the environment
+#[cfg(target_os = "linux")]
+fn enable_profiling(ops: &mut dynasmrt::Assembler) {
+    if std::env::var_os("DYNASM_PROFILE").is_none() {
+        return;
+    }
+    ops.enable_perf_map().unwrap();
+    ops.enable_jitdump().unwrap();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_profiling(_ops: &mut dynasmrt::Assembler) { }
+
+#[cfg(unix)]
+fn install_fault_handler() {
+    dynasmrt::install_fault_handler().unwrap();
+}
+
+#[cfg(not(unix))]
+fn install_fault_handler() { }
@@ -1,7 +1,53 @@
+#![feature(const_fn)]
+
+//! The x64 instruction tables that the `dynasm` plugin assembles instructions with. They are
+//! shared with the disassembler of `dynasmrt`, so both always agree on the instruction set.
+//! Nothing in here should be counted on to be stable.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate bitflags;
+
 use std::collections::{HashMap, hash_map};
+use std::slice;
+use std::iter;
 
-use compiler::Opdata;
+use self::flags::Flags;
 
+/*
+ * Instruction encoding data formats
+ */
+
+pub struct Opdata {
+    pub args:  &'static [u8],  // format string of arg format
+    pub ops:   &'static [u8],
+    pub reg:   u8,
+    pub flags: Flags
+}
+
+pub struct FormatStringIterator<'a> {
+    inner: iter::Cloned<slice::Iter<'a, u8>>
+}
+
+impl<'a> FormatStringIterator<'a> {
+    pub fn new(buf: &'a [u8]) -> FormatStringIterator<'a> {
+        FormatStringIterator {inner: buf.into_iter().cloned()}
+    }
+}
+
+impl<'a> Iterator for FormatStringIterator<'a> {
+    type Item = (u8, u8);
+
+    fn next(&mut self) -> Option<(u8, u8)> {
+        if let Some(ty) = self.inner.next() {
+            let size = self.inner.next().expect("Invalid format string data");
+            Some((ty, size))
+        } else {
+            None
+        }
+    }
+}
 
 macro_rules! constify {
     ($t:ty, $e:expr) => { {const C: &'static $t = &$e; C} }